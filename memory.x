@@ -5,8 +5,18 @@ MEMORY
   /* These values correspond to the LM3S6965, one of the few devices QEMU can emulate */
   FLASH : ORIGIN = 0x08000000, LENGTH = 256K
   RAM : ORIGIN = 0x20000000, LENGTH = 64K
+  /* SRAM2 keeps its content across system resets (unless the SRAM2_RST option bit is cleared) */
+  SRAM2 : ORIGIN = 0x10000000, LENGTH = 32K
 }
 
+/* Data that must survive resets, see history.rs. Not initialized by the runtime! */
+SECTIONS {
+  .sram2 (NOLOAD) : ALIGN(4) {
+    *(.sram2);
+    . = ALIGN(4);
+  } > SRAM2
+} INSERT AFTER .bss;
+
 /* This is where the call stack will be allocated. */
 /* The stack is of the full descending type. */
 /* You may want to use this variable to locate the call stack and static
@@ -0,0 +1,18 @@
+//! Pin assignments of the supported boards. Exactly one of the `board-*` features selects which
+//! LED types `hw::Leds` is made of, and which pin `hw::wait_for_button_press` reads.
+
+#[cfg(all(feature = "board-nucleo-l452", feature = "board-custom"))]
+compile_error!("Only one of the `board-nucleo-l452` and `board-custom` features can be enabled");
+
+#[cfg(not(any(feature = "board-nucleo-l452", feature = "board-custom")))]
+compile_error!("Enable one of the `board-nucleo-l452` and `board-custom` features");
+
+#[cfg(feature = "board-nucleo-l452")]
+mod nucleo_l452;
+#[cfg(feature = "board-nucleo-l452")]
+pub use nucleo_l452::*;
+
+#[cfg(feature = "board-custom")]
+mod custom;
+#[cfg(feature = "board-custom")]
+pub use custom::*;
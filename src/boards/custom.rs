@@ -0,0 +1,96 @@
+use crate::pac;
+
+use crate::hw::{ButtonDriver, LedDriver};
+
+// Edit these for your board. Ports are numbered from 0 (GPIOA) to 7 (GPIOH)
+pub type GreenLed = GpioLed<2, 7>;
+pub type RedLed = GpioLed<1, 14>;
+pub type BlueLed = GpioLed<1, 1>;
+pub type UserButton = GpioButton<2, 13>;
+
+const GPIO_BASE: u32 = 0x4800_0000;
+const GPIO_PORT_STRIDE: u32 = 0x400;
+const GPIO_MODER_OFFSET: u32 = 0x00;
+const GPIO_PUPDR_OFFSET: u32 = 0x0C;
+const GPIO_IDR_OFFSET: u32 = 0x10;
+const GPIO_BSRR_OFFSET: u32 = 0x18;
+
+/// Enables the clock of GPIO port `port` in `RCC_AHB2ENR`
+fn enable_port_clock(port: u8) {
+    // SAFETY: Only the clock enable of the port is changed. GPIOAEN to GPIOHEN are bits 0 to 7,
+    // which the callers check `port` against
+    let peripherals = unsafe { pac::Peripherals::steal() };
+    peripherals
+        .RCC
+        .ahb2enr
+        .modify(|r, w| unsafe { w.bits(r.bits() | 1 << port) });
+}
+
+/// An active-high LED on any GPIO pin. The GPIO ports are not all the same type in the PAC, so
+/// the registers are accessed by address
+pub struct GpioLed<const PORT: u8, const PIN: u8>(());
+
+impl<const PORT: u8, const PIN: u8> GpioLed<PORT, PIN> {
+    const PORT_BASE: u32 = GPIO_BASE + PORT as u32 * GPIO_PORT_STRIDE;
+
+    pub fn new() -> Self {
+        assert!(PORT < 8 && PIN < 16);
+
+        enable_port_clock(PORT);
+
+        // General purpose output mode is 0b01
+        let moder = (Self::PORT_BASE + GPIO_MODER_OFFSET) as *mut u32;
+        // SAFETY: GPIOx_MODER of an existing port, where only the two bits of PIN are changed
+        unsafe {
+            let value = moder.read_volatile() & !(0b11 << (2 * PIN));
+            moder.write_volatile(value | 0b01 << (2 * PIN));
+        }
+
+        GpioLed(())
+    }
+}
+
+impl<const PORT: u8, const PIN: u8> LedDriver for GpioLed<PORT, PIN> {
+    fn set(&mut self, on: bool) {
+        // The lower half of BSRR sets the pin, the upper half resets it
+        let bsrr = (Self::PORT_BASE + GPIO_BSRR_OFFSET) as *mut u32;
+        let bit = if on { 1 << PIN } else { 1 << (PIN + 16) };
+        // SAFETY: BSRR is write-only and only affects the pins whose bits are set, so this can't
+        // change any other pin
+        unsafe { bsrr.write_volatile(bit) };
+    }
+}
+
+/// An active-low button on any GPIO pin, read with the internal pull-up
+pub struct GpioButton<const PORT: u8, const PIN: u8>(());
+
+impl<const PORT: u8, const PIN: u8> GpioButton<PORT, PIN> {
+    const PORT_BASE: u32 = GPIO_BASE + PORT as u32 * GPIO_PORT_STRIDE;
+
+    pub fn new() -> Self {
+        assert!(PORT < 8 && PIN < 16);
+
+        enable_port_clock(PORT);
+
+        // Input mode is 0b00, pull-up is 0b01
+        let moder = (Self::PORT_BASE + GPIO_MODER_OFFSET) as *mut u32;
+        let pupdr = (Self::PORT_BASE + GPIO_PUPDR_OFFSET) as *mut u32;
+        // SAFETY: GPIOx_MODER and GPIOx_PUPDR of an existing port, where only the two bits of PIN
+        // are changed
+        unsafe {
+            moder.write_volatile(moder.read_volatile() & !(0b11 << (2 * PIN)));
+            let value = pupdr.read_volatile() & !(0b11 << (2 * PIN));
+            pupdr.write_volatile(value | 0b01 << (2 * PIN));
+        }
+
+        GpioButton(())
+    }
+}
+
+impl<const PORT: u8, const PIN: u8> ButtonDriver for GpioButton<PORT, PIN> {
+    fn is_pressed(&self) -> bool {
+        let idr = (Self::PORT_BASE + GPIO_IDR_OFFSET) as *const u32;
+        // SAFETY: GPIOx_IDR is read-only
+        unsafe { idr.read_volatile() & (1 << PIN) == 0 }
+    }
+}
@@ -0,0 +1,90 @@
+use crate::pac;
+
+use crate::hw::{ButtonDriver, LedDriver};
+
+/// Green LED on PC7
+pub struct GreenLed(());
+
+impl GreenLed {
+    pub fn new() -> Self {
+        // SAFETY: Only the GPIOC clock enable and the mode of PC7 are changed
+        let peripherals = unsafe { pac::Peripherals::steal() };
+        peripherals.RCC.ahb2enr.modify(|_, w| w.gpiocen().set_bit());
+        peripherals.GPIOC.moder.modify(|_, w| w.moder7().output());
+        GreenLed(())
+    }
+}
+
+impl LedDriver for GreenLed {
+    fn set(&mut self, on: bool) {
+        // SAFETY: Only PC7 is changed. Nothing changes GPIOC_ODR from an interrupt, so the
+        // read-modify-write can't lose an update
+        let peripherals = unsafe { pac::Peripherals::steal() };
+        peripherals.GPIOC.odr.modify(|_, w| w.odr7().bit(on));
+    }
+}
+
+/// Red LED on PB14
+pub struct RedLed(());
+
+impl RedLed {
+    pub fn new() -> Self {
+        // SAFETY: Only the GPIOB clock enable and the mode of PB14 are changed
+        let peripherals = unsafe { pac::Peripherals::steal() };
+        peripherals.RCC.ahb2enr.modify(|_, w| w.gpioben().set_bit());
+        peripherals.GPIOB.moder.modify(|_, w| w.moder14().output());
+        RedLed(())
+    }
+}
+
+impl LedDriver for RedLed {
+    fn set(&mut self, on: bool) {
+        // SAFETY: Only PB14 is changed, like for the green LED
+        let peripherals = unsafe { pac::Peripherals::steal() };
+        peripherals.GPIOB.odr.modify(|_, w| w.odr14().bit(on));
+    }
+}
+
+/// Blue LED on PB1
+pub struct BlueLed(());
+
+impl BlueLed {
+    pub fn new() -> Self {
+        // SAFETY: Only the GPIOB clock enable and the mode of PB1 are changed
+        let peripherals = unsafe { pac::Peripherals::steal() };
+        peripherals.RCC.ahb2enr.modify(|_, w| w.gpioben().set_bit());
+        peripherals.GPIOB.moder.modify(|_, w| w.moder1().output());
+        BlueLed(())
+    }
+}
+
+impl LedDriver for BlueLed {
+    fn set(&mut self, on: bool) {
+        // SAFETY: Only PB1 is changed, like for the green LED
+        let peripherals = unsafe { pac::Peripherals::steal() };
+        peripherals.GPIOB.odr.modify(|_, w| w.odr1().bit(on));
+    }
+}
+
+/// User button on PC13, which pulls the pin low when pressed
+pub struct UserButton(());
+
+impl UserButton {
+    pub fn new() -> Self {
+        // SAFETY: Only the GPIOC clock enable and the PC13 bits are changed, the LED on PC7 keeps
+        // its configuration
+        let peripherals = unsafe { pac::Peripherals::steal() };
+        peripherals.RCC.ahb2enr.modify(|_, w| w.gpiocen().set_bit());
+        peripherals.GPIOC.moder.modify(|_, w| w.moder13().input());
+        peripherals.GPIOC.pupdr.modify(|_, w| w.pupdr13().pull_up());
+        UserButton(())
+    }
+}
+
+impl ButtonDriver for UserButton {
+    fn is_pressed(&self) -> bool {
+        // SAFETY: GPIOC is only read
+        let peripherals = unsafe { pac::Peripherals::steal() };
+        peripherals.GPIOC.idr.read().idr13().bit_is_clear()
+    }
+}
@@ -0,0 +1,90 @@
+//! Parsing of the target configuration that can be sent via RTT, see `CONFIG_MAGIC_VALUE` in
+//! `main`.
+
+/// A command to change the target at runtime
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetCommand {
+    /// `addr=<address> range=<range>`: target this address instead of `TARGET_ADDRESSES`
+    Set { address: usize, range: usize },
+    /// `clear`: go back to `TARGET_ADDRESSES`
+    Clear,
+}
+
+/// Parses a number in hex (with `0x` prefix) or decimal
+fn parse_number(text: &str) -> Option<usize> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// Parses a line like `addr=0x10000 range=0x20` or `clear`. The order of the fields doesn't
+/// matter, but both must be given. Only the syntax is checked, not whether the values make sense.
+pub fn parse_target_command(line: &str) -> Option<TargetCommand> {
+    let line = line.trim();
+    if line == "clear" {
+        return Some(TargetCommand::Clear);
+    }
+
+    let mut address = None;
+    let mut range = None;
+    for field in line.split_whitespace() {
+        let mut parts = field.splitn(2, '=');
+        let value = match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => (key, parse_number(value)?),
+            _ => return None,
+        };
+
+        match value {
+            ("addr", value) => address = Some(value),
+            ("range", value) => range = Some(value),
+            _ => return None,
+        }
+    }
+
+    Some(TargetCommand::Set {
+        address: address?,
+        range: range?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_hex_fields() {
+        assert_eq!(
+            parse_target_command("addr=0x10000 range=0x20\n"),
+            Some(TargetCommand::Set {
+                address: 0x10000,
+                range: 0x20
+            })
+        );
+    }
+
+    #[test]
+    fn parses_decimal_fields_in_any_order() {
+        assert_eq!(
+            parse_target_command("range=32 addr=65536"),
+            Some(TargetCommand::Set {
+                address: 0x10000,
+                range: 0x20
+            })
+        );
+    }
+
+    #[test]
+    fn parses_clear() {
+        assert_eq!(parse_target_command("clear\r\n"), Some(TargetCommand::Clear));
+    }
+
+    #[test]
+    fn rejects_incomplete_or_unknown_fields() {
+        assert_eq!(parse_target_command("addr=0x10000"), None);
+        assert_eq!(parse_target_command("addr=0x10000 range=0x20 x=1"), None);
+        assert_eq!(parse_target_command("addr=0xZZ range=0x20"), None);
+        assert_eq!(parse_target_command("addr range=0x20"), None);
+        assert_eq!(parse_target_command(""), None);
+    }
+}
@@ -1,9 +1,24 @@
+#![deny(unsafe_op_in_unsafe_fn)]
+
 use core::ops::Deref;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use cortex_m::asm::{dmb, dsb, isb};
+
+use crate::pac;
 
-use cortex_m::asm::dmb;
-use stm32l4::stm32l4x1;
+// Not used by the firmware itself, as the check bits can't be read on the STM32L4
+#[allow(dead_code)]
+pub mod ecc;
+#[allow(dead_code)]
+pub mod ecc_inject;
+#[cfg(test)]
+pub mod mock;
+pub mod sector_map;
 
-#[derive(Debug, Clone, Copy)]
+use sector_map::SectorMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Error {
     /// Unlocking the flash failed. This should never happen and requires a reset to escape from
     UnlockFailed = 0b1,
@@ -13,11 +28,255 @@ pub enum Error {
     Illegal = 0b11,
     /// The given page number does not exist in the current bank mode.
     InvalidPage = 0b100,
+    /// The page is write-protected by the WRP option bytes and cannot be erased or written.
+    /// See [Flash::is_page_write_protected] for how to remove the protection.
+    WriteProtection = 0b101,
+    /// Programming failed with `PROGERR`, because the target double-word was not erased before.
+    ProgrammingSequence = 0b110,
+    /// The target address is not aligned to a double-word (8 bytes).
+    ProgrammingAlignment = 0b111,
+    /// The DMA transfer of [FlashProgram::write_dwords_dma] reported a transfer error.
+    DmaTransfer = 0b1000,
+    /// An intentionally illegal write was accepted by the flash controller instead of ending in
+    /// `SIZERR`, see [FlashProgram::write_word_illegal].
+    NotRejected = 0b1001,
+    /// The given DMA channel does not exist, DMA1 has the channels 1 to 7.
+    InvalidChannel = 0b1010,
+    /// Programming the option bytes set `OPTVERR`: the values in the option registers are not
+    /// valid and would not be loaded as written.
+    OptionValidity = 0b1011,
+    /// The option bytes still differ from what was programmed after they were reloaded, e.g.
+    /// because the bits don't exist on this device.
+    OptionsNotApplied = 0b1100,
+}
+
+/// An ECC error detected by [Flash::try_read_dword]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EccError {
+    /// A single bit error was detected and corrected, the read value is correct
+    Corrected { address: u32 },
+    /// A double bit error was detected, the read value is wrong
+    Uncorrectable { address: u32 },
+}
+
+/// What is known about a successful corruption, for post-mortem analysis.
+///
+/// There is no syndrome in here: `FLASH_ECCR` only records the address and the kind of the
+/// error, the ECC bits themselves are not visible to software on the STM32L4.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CorruptionResult {
+    /// Offset of the failing double-word within its bank
+    pub ecc_address: u32,
+    /// 1 or 2
+    pub bank: u8,
+    /// Whether the error was uncorrectable (ECCD) rather than corrected (ECCC)
+    pub is_double_bit: bool,
+    /// The waiting time of the attempt that caused the error
+    pub timing_middle: u32,
+}
+
+impl CorruptionResult {
+    /// Builds the result from an error reported by [Flash::try_read_dword] or
+    /// [pending_ecc_error]
+    pub fn from_ecc_error(error: EccError, timing_middle: u32) -> Self {
+        let (address, is_double_bit) = match error {
+            EccError::Corrected { address } => (address, false),
+            EccError::Uncorrectable { address } => (address, true),
+        };
+
+        CorruptionResult {
+            ecc_address: address & Flash::ECCR_ADDR_MASK,
+            bank: if address & Flash::ECC_ADDRESS_BANK2 != 0 {
+                2
+            } else {
+                1
+            },
+            is_double_bit,
+            timing_middle,
+        }
+    }
+}
+
+/// Read-out protection level, decoded from the `RDP` field of `FLASH_OPTR`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RdpLevel {
+    /// 0xAA: no protection
+    Level0,
+    /// 0xBB: flash can't be read by the debugger or the bootloader
+    Level1,
+    /// 0xCC: like level 1, and the debug interface is disabled for good
+    Level2,
+    /// Any other value. The hardware treats these like level 1
+    Unknown(u8),
+}
+
+impl From<u8> for RdpLevel {
+    fn from(rdp: u8) -> Self {
+        match rdp {
+            0xAA => RdpLevel::Level0,
+            0xBB => RdpLevel::Level1,
+            0xCC => RdpLevel::Level2,
+            other => RdpLevel::Unknown(other),
+        }
+    }
+}
+
+/// Set while [Flash::try_read_dword] is reading, so that the NMI handler knows that the ECC error
+/// is expected and must not be treated as a crash
+static ECC_PROBE_ACTIVE: AtomicBool = AtomicBool::new(false);
+/// `FLASH_ECCR` as seen by the NMI handler during a probe
+static ECC_PROBE_ECCR: AtomicU32 = AtomicU32::new(0);
+
+/// Must be called first thing in the NMI handler. If a [Flash::try_read_dword] probe is running,
+/// this records and clears the ECC error and returns `true`, in which case the handler should
+/// just return.
+pub fn handle_ecc_nmi_during_probe() -> bool {
+    if !ECC_PROBE_ACTIVE.load(Ordering::SeqCst) {
+        return false;
+    }
+
+    // SAFETY: The probe is waiting for the NMI and doesn't touch FLASH_ECCR until we return
+    let peripherals = unsafe { pac::Peripherals::steal() };
+    let eccr = &peripherals.FLASH.eccr;
+    ECC_PROBE_ECCR.store(eccr.read().bits(), Ordering::SeqCst);
+
+    // ECCD and ECCC are cleared by writing 1, otherwise the NMI would fire again right away
+    eccr.modify(|_, w| w.eccd().set_bit().eccc().set_bit());
+
+    true
+}
+
+/// Returns the last uncorrectable ECC error recorded in `FLASH_ECCR`, for exception handlers that
+/// don't have access to the [Flash] instance.
+pub fn pending_ecc_error() -> Option<EccError> {
+    // SAFETY: FLASH_ECCR is only read, which has no side effects
+    let peripherals = unsafe { pac::Peripherals::steal() };
+    let eccr = peripherals.FLASH.eccr.read().bits();
+
+    if eccr & Flash::ECCR_ECCD != 0 {
+        Some(EccError::Uncorrectable {
+            address: Flash::ecc_error_address(eccr),
+        })
+    } else {
+        None
+    }
 }
 
 /// Abstracts interaction with the flash hardware
 pub struct Flash {
-    flash: stm32l4x1::FLASH,
+    flash: pac::FLASH,
+    sysclk_hz: u32,
+}
+
+/// A page number that has been validated against the page count of the current device.
+/// It can only be created through [Flash::make_page], so a page that does not exist can never be
+/// passed to [FlashProgram::erase_page].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlashPage(u32);
+
+impl FlashPage {
+    /// The page number, counting from the start of the flash
+    pub fn number(&self) -> u32 {
+        self.0
+    }
+}
+
+/// Snapshot of the user option bytes as found in `FLASH_OPTR`.
+/// Use [Flash::option_bytes_read] to get the current value, modify it and then write it back
+/// with [FlashUnlocked::option_bytes_write].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OptionBytes {
+    pub raw: u32,
+}
+
+impl OptionBytes {
+    /// RDP field, bits 0-7
+    const RDP_MASK: u32 = 0xFF;
+    /// BFB2 bit. Like DUALBANK, this only exists on dual-bank capable devices.
+    const BFB2: u32 = 1 << 20;
+    /// DUALBANK bit. This bit only exists on dual-bank capable devices, on all others it is
+    /// reserved and reads as 0, which is the same as single-bank mode.
+    const DUALBANK: u32 = 1 << 21;
+    /// nBOOT0 bit
+    const NBOOT0: u32 = 1 << 27;
+
+    /// Raw read protection level: 0xAA is level 0, 0xCC is level 2, anything else is level 1
+    pub fn rdp_level(&self) -> u8 {
+        (self.raw & OptionBytes::RDP_MASK) as u8
+    }
+
+    /// Sets the raw read protection level. Be careful: going from level 1 to level 0 erases the
+    /// whole flash, and level 2 (0xCC) can never be left again.
+    pub fn set_rdp_level(&mut self, rdp: u8) {
+        self.raw = (self.raw & !OptionBytes::RDP_MASK) | rdp as u32;
+    }
+
+    /// Whether the flash is organized as two banks
+    pub fn dual_bank(&self) -> bool {
+        self.raw & OptionBytes::DUALBANK != 0
+    }
+
+    /// Sets the DUALBANK bit
+    pub fn set_dual_bank(&mut self, dual_bank: bool) {
+        self.set_bit(OptionBytes::DUALBANK, dual_bank);
+    }
+
+    /// Whether booting from bank 2 is enabled
+    pub fn bfb2(&self) -> bool {
+        self.raw & OptionBytes::BFB2 != 0
+    }
+
+    /// Sets the BFB2 bit
+    pub fn set_bfb2(&mut self, bfb2: bool) {
+        self.set_bit(OptionBytes::BFB2, bfb2);
+    }
+
+    /// The nBOOT0 bit, which selects the boot mode together with nSWBOOT0
+    pub fn nboot0(&self) -> bool {
+        self.raw & OptionBytes::NBOOT0 != 0
+    }
+
+    fn set_bit(&mut self, mask: u32, value: bool) {
+        if value {
+            self.raw |= mask;
+        } else {
+            self.raw &= !mask;
+        }
+    }
+}
+
+/// Option bytes to enforce at startup, see the setters of [OptionBytes] for what they do. Fields
+/// that are `None` keep their current value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OptionBytesConfig {
+    pub rdp_level: Option<u8>,
+    pub dual_bank: Option<bool>,
+    pub bfb2: Option<bool>,
+}
+
+impl OptionBytesConfig {
+    /// Returns `opts` with the configured fields changed
+    pub fn apply(&self, mut opts: OptionBytes) -> OptionBytes {
+        if let Some(rdp) = self.rdp_level {
+            opts.set_rdp_level(rdp);
+        }
+        if let Some(dual_bank) = self.dual_bank {
+            opts.set_dual_bank(dual_bank);
+        }
+        if let Some(bfb2) = self.bfb2 {
+            opts.set_bfb2(bfb2);
+        }
+
+        opts
+    }
+}
+
+/// A PCROP area in pages of bank 1, see [FlashUnlocked::pcrop_set_region]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PcropRegion {
+    pub start_page: u32,
+    pub end_page: u32,
+    pub rdp_prot: bool,
 }
 
 /// Represents a Flash object that has been unlocked for programming.
@@ -26,6 +285,16 @@ pub struct FlashUnlocked<'a> {
     flash: &'a mut Flash,
 }
 
+/// Unlocks the flash, runs `f` on it and locks it again, also when `f` fails. Fails if unlocking
+/// fails, or with the error returned by `f`, so that `f` can use `?`.
+pub fn with_flash_unlocked<F, R>(flash: &mut Flash, f: F) -> Result<R, Error>
+where
+    F: FnOnce(&mut FlashUnlocked) -> Result<R, Error>,
+{
+    let mut unlocked = flash.unlock()?;
+    f(&mut unlocked)
+}
+
 #[cfg(not(kani))]
 impl<'a> Drop for FlashUnlocked<'a> {
     /// The destructor for this object locks the flash
@@ -56,15 +325,138 @@ impl Flash {
     const FLASH_KEY1: u32 = 0x4567_0123;
     /// Constant value from STM Documentation
     const FLASH_KEY2: u32 = 0xCDEF_89AB;
+    /// Address of the flash size data register (see "Flash size data register" in the manual).
+    /// The lower 16 bits contain the flash size in KB.
+    const FLASH_SIZE_REGISTER: *const u16 = 0x1FFF_75E0 as *const u16;
+    /// Base address of the 96 bit unique device ID
+    const UID_BASE: *const u32 = 0x1FFF_7590 as *const u32;
+    /// ADDR_ECC field in `FLASH_ECCR`
+    const ECCR_ADDR_MASK: u32 = 0x7_FFFF;
+    /// BK_ECC bit in `FLASH_ECCR`, set if the error is in bank 2
+    const ECCR_BK_ECC: u32 = 1 << 19;
+    /// Bit that marks bank 2 in the addresses returned in [EccError]
+    const ECC_ADDRESS_BANK2: u32 = 1 << 20;
+    /// ECCC bit in `FLASH_ECCR`
+    const ECCR_ECCC: u32 = 1 << 30;
+    /// ECCD bit in `FLASH_ECCR`
+    const ECCR_ECCD: u32 = 1 << 31;
+    /// Constant value from STM Documentation, unlocks the option bytes
+    const OPT_KEY1: u32 = 0x0819_2A3B;
+    /// Constant value from STM Documentation, unlocks the option bytes
+    const OPT_KEY2: u32 = 0x4C5D_6E7F;
+    /// MER1 bit in `FLASH_CR`, erases bank 1 (or the whole flash in single-bank mode)
+    const CR_MER1: u32 = 1 << 2;
+    /// MER2 bit in `FLASH_CR`. Like DUALBANK, this is missing from the stm32l4x1 PAC
+    const CR_MER2: u32 = 1 << 15;
+    /// Offsets of `FLASH_WRP2AR` and `FLASH_WRP2BR`, the write protection areas of bank 2. Like
+    /// MER2, these registers are missing from the stm32l4x1 PAC
+    const WRP2AR_OFFSET: usize = 0x4C;
+    const WRP2BR_OFFSET: usize = 0x50;
+    /// `FLASH_PCROP1SR` and `FLASH_PCROP1ER` count in double-words
+    const PCROP_GRANULARITY: u32 = 8;
+    /// Timeout of [FlashUnlocked::wait], the longest page operation (a page erase) takes 24.5ms
+    const OPERATION_TIMEOUT_MS: u32 = 25;
+    /// Timeout for [FlashUnlocked::erase_bank]. A bank erase takes up to 25ms, the margin is there
+    /// because giving up in the middle of it leaves the bank in an unknown state
+    const BANK_ERASE_TIMEOUT_MS: u32 = 50;
+
+    /// Create flash interaction abstraction from HAL object, for the reset clock configuration
+    pub fn new(flash: pac::FLASH) -> Self {
+        Self::with_sysclk_hz(flash, crate::hw::SYSCLK_HZ)
+    }
+
+    /// Like [Flash::new], for when the system clock was changed, e.g. by
+    /// [crate::hw::init_clock_hsi16]. The clock is needed to keep the timeout of
+    /// [FlashUnlocked::wait] the same
+    pub fn with_sysclk_hz(flash: pac::FLASH, sysclk_hz: u32) -> Self {
+        Flash { flash, sysclk_hz }
+    }
+
+    /// Returns whether the flash is configured in dual-bank mode, according to the DUALBANK bit
+    /// in the option register.
+    pub fn is_dual_bank(&self) -> bool {
+        self.option_bytes_read().dual_bank()
+    }
+
+    /// Reads the currently active option bytes from `FLASH_OPTR`.
+    /// This does not require the flash to be unlocked.
+    pub fn option_bytes_read(&self) -> OptionBytes {
+        OptionBytes {
+            raw: self.flash.optr.read().bits(),
+        }
+    }
+
+    /// Page size in bytes: 2 KB in single-bank mode, 4 KB in dual-bank mode
+    pub fn page_size_bytes(&self) -> u32 {
+        if self.is_dual_bank() {
+            0x1000
+        } else {
+            0x800
+        }
+    }
+
+    /// Total number of pages, derived from the device flash size stored in the flash size data
+    /// register. E.g. a 512 KB STM32L451 has 256 pages in single-bank mode.
+    pub fn page_count(&self) -> u32 {
+        Flash::size_bytes() / self.page_size_bytes()
+    }
+
+    /// Reads the factory-programmed 96 bit unique device ID, lowest word first
+    pub fn read_unique_device_id(&self) -> [u8; 12] {
+        let mut uid = [0; 12];
+        for (i, chunk) in uid.chunks_exact_mut(4).enumerate() {
+            // SAFETY: The UID lies in the read-only system memory, which exists on all STM32L4
+            let word = unsafe { core::ptr::read_volatile(Flash::UID_BASE.add(i)) };
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        uid
+    }
+
+    /// Reads the device ID (`DEV_ID`) from `DBGMCU_IDCODE`, e.g. 0x462 for the STM32L45x/L46x
+    pub fn read_device_id(&self) -> u16 {
+        // SAFETY: DBGMCU_IDCODE is only read, which has no side effects
+        let peripherals = unsafe { pac::Peripherals::steal() };
+        peripherals.DBGMCU.idcode.read().dev_id().bits()
+    }
+
+    /// Returns an iterator over all pages of the flash, in the current bank mode
+    pub fn sector_map(&self) -> SectorMap {
+        SectorMap::new(
+            self.page_size_bytes(),
+            self.page_count(),
+            self.is_dual_bank(),
+        )
+    }
+
+    /// Size of the flash, from the flash size data register
+    fn size_bytes() -> u32 {
+        // SAFETY: The flash size data register is a read-only location in the system memory,
+        // which exists on all STM32L4 devices
+        let size_kb = unsafe { core::ptr::read_volatile(Flash::FLASH_SIZE_REGISTER) } as u32;
+
+        size_kb * 1024
+    }
+
+    /// Whether the `len` bytes starting at `address` lie within the flash, either at
+    /// [crate::hw::FLASH_BASE] or in the alias at address 0 that we boot from
+    fn contains(&self, address: usize, len: usize) -> bool {
+        range_in_flash(address, len, Flash::size_bytes() as usize)
+    }
 
-    /// Create flash interaction abstraction from HAL object
-    pub fn new(flash: stm32l4x1::FLASH) -> Self {
-        Flash { flash }
+    /// Writes one of the keys of the unlock sequence to `FLASH_KEYR`
+    fn write_flash_key(&self, key: u32) {
+        // SAFETY: FLASH_KEYR accepts any value, a wrong key just keeps the flash locked
+        self.flash.keyr.write(|w| unsafe { w.keyr().bits(key) });
+        dmb();
     }
 
-    /// Page size
-    pub fn page_size(&self) -> u32 {
-        0x800
+    /// Writes one of the keys of the option byte unlock sequence to `FLASH_OPTKEYR`
+    fn write_option_key(&self, key: u32) {
+        // SAFETY: FLASH_OPTKEYR accepts any value, a wrong key just keeps the option bytes locked
+        self.flash
+            .optkeyr
+            .write(|w| unsafe { w.optkeyr().bits(key) });
+        dmb();
     }
 
     /// Reads the current flash status:
@@ -86,14 +478,8 @@ impl Flash {
     /// Unlock the flash according to the unlock sequence (see 3.3.5 Flash program and erase operations).
     /// The returned object, if [Ok], will automatically relock the flash once it gets dropped (RAII).
     pub fn unlock(&mut self) -> Result<FlashUnlocked, Error> {
-        self.flash
-            .keyr
-            .write(|w| unsafe { w.keyr().bits(Flash::FLASH_KEY1) });
-        dmb();
-        self.flash
-            .keyr
-            .write(|w| unsafe { w.keyr().bits(Flash::FLASH_KEY2) });
-        dmb();
+        self.write_flash_key(Flash::FLASH_KEY1);
+        self.write_flash_key(Flash::FLASH_KEY2);
 
         // Lock bit:
         // When set, the FLASH_CR register is locked. It is cleared by
@@ -108,9 +494,273 @@ impl Flash {
         Ok(FlashUnlocked { flash: self })
     }
 
-    /// Returns the page number for a given address, depending on the [Flash::page_size]
+    /// Checks whether the given page lies within one of the write-protected areas configured in
+    /// `FLASH_WRP1AR` and `FLASH_WRP1BR` (see 3.5.2 "Write protection (WRP)"). Pages in bank 2
+    /// are checked against `FLASH_WRP2AR` and `FLASH_WRP2BR` instead, whose areas count from the
+    /// start of bank 2.
+    ///
+    /// An area is enabled when its start page is less than or equal to its end page; if start is
+    /// greater than end, the area is disabled.
+    ///
+    /// Write protection can only be removed by reprogramming the option bytes: unlock `FLASH_CR`,
+    /// unlock the option bytes via `FLASH_OPTKEYR`, write `WRP1x_STRT > WRP1x_END` (e.g. start
+    /// 0xFF, end 0x00), set `OPTSTRT`, wait for `BSY` to clear and then set `OBL_LAUNCH` (or
+    /// power cycle) to reload the option bytes. STM32CubeProgrammer can do the same from the
+    /// "Option bytes" tab.
+    pub fn is_page_write_protected(&self, page: u32) -> bool {
+        let (second_bank, page_in_bank) = self.page_in_bank(page);
+
+        let in_area = |start: u8, end: u8| {
+            start <= end && (start as u32..=end as u32).contains(&page_in_bank)
+        };
+
+        if second_bank {
+            // Same layout as the WRP1 registers: STRT in bits 0-7, END in bits 16-23
+            let read = |offset| {
+                // SAFETY: A read-only access to a FLASH register, which has no side effects
+                let raw = unsafe {
+                    core::ptr::read_volatile((pac::FLASH::ptr() as usize + offset) as *const u32)
+                };
+                (raw as u8, (raw >> 16) as u8)
+            };
+            let (wrp2a_strt, wrp2a_end) = read(Flash::WRP2AR_OFFSET);
+            let (wrp2b_strt, wrp2b_end) = read(Flash::WRP2BR_OFFSET);
+
+            return in_area(wrp2a_strt, wrp2a_end) || in_area(wrp2b_strt, wrp2b_end);
+        }
+
+        let wrp1a = self.flash.wrp1ar.read();
+        let wrp1b = self.flash.wrp1br.read();
+
+        in_area(wrp1a.wrp1a_strt().bits(), wrp1a.wrp1a_end().bits())
+            || in_area(wrp1b.wrp1b_strt().bits(), wrp1b.wrp1b_end().bits())
+    }
+
+    /// Splits a page number into the bank (`true` for bank 2) and the page within that bank. In
+    /// dual-bank mode, page numbers continue from bank 1 into bank 2. The page number is
+    /// physical, so a bank swap doesn't matter here (see [Flash::address_to_page_number])
+    fn page_in_bank(&self, page: u32) -> (bool, u32) {
+        let pages_per_bank = if self.is_dual_bank() {
+            self.page_count() / 2
+        } else {
+            self.page_count()
+        };
+
+        (page >= pages_per_bank, page % pages_per_bank)
+    }
+
+    /// Returns the read-out protection level. This only reads `FLASH_OPTR`, so it works while the
+    /// flash is locked.
+    pub fn read_protection_level(&self) -> RdpLevel {
+        RdpLevel::from(self.option_bytes_read().rdp_level())
+    }
+
+    /// Checks whether a proprietary code readout protection (PCROP) area is configured in
+    /// `FLASH_PCROP1SR` and `FLASH_PCROP1ER`. Like the write protection areas, the area is
+    /// disabled if its start is greater than its end.
+    pub fn is_pcrop_active(&self) -> bool {
+        let start = self.flash.pcrop1sr.read().pcrop1_strt().bits();
+        let end = self.flash.pcrop1er.read().pcrop1_end().bits();
+
+        start <= end
+    }
+
+    /// Reads the PCROP area from `FLASH_PCROP1SR` and `FLASH_PCROP1ER` as the first and last
+    /// page it covers. The registers have double-word granularity, partially covered pages are
+    /// included. If the area is disabled, the start is greater than the end.
+    pub fn pcrop_read_region(&self) -> (u32, u32) {
+        let start = self.flash.pcrop1sr.read().pcrop1_strt().bits() as u32;
+        let end = self.flash.pcrop1er.read().pcrop1_end().bits() as u32;
+        let dwords_per_page = self.page_size_bytes() / Flash::PCROP_GRANULARITY;
+
+        (start / dwords_per_page, end / dwords_per_page)
+    }
+
+    /// Reads the PCROP area like [Flash::pcrop_read_region], together with `PCROP_RDP`. Returns
+    /// [None] if the area is disabled.
+    pub fn pcrop_read(&self) -> Option<PcropRegion> {
+        if !self.is_pcrop_active() {
+            return None;
+        }
+
+        let (start_page, end_page) = self.pcrop_read_region();
+        Some(PcropRegion {
+            start_page,
+            end_page,
+            rdp_prot: self.flash.pcrop1er.read().pcrop_rdp().bit_is_set(),
+        })
+    }
+
+    /// Sets `ECCIE` in `FLASH_ECCR`. According to the reference manual, this enables the
+    /// interrupt for corrected single bit errors (ECCC); uncorrectable double bit errors (ECCD)
+    /// always raise an NMI, independent of this bit. Setting it explicitly makes sure we don't
+    /// depend on whatever state a previous program left the flash interface in.
+    pub fn enable_ecc_nmi(&mut self) {
+        // ECCD and ECCC are cleared by writing 1, so writing back what was read would clear them
+        self.flash
+            .eccr
+            .modify(|_, w| w.eccie().set_bit().eccd().clear_bit().eccc().clear_bit());
+    }
+
+    /// Clears `ECCIE` in `FLASH_ECCR`, see [Flash::enable_ecc_nmi]. Pending flags are kept
+    pub fn disable_ecc_nmi(&mut self) {
+        self.flash
+            .eccr
+            .modify(|_, w| w.eccie().clear_bit().eccd().clear_bit().eccc().clear_bit());
+    }
+
+    /// Sets or clears `PRFTEN` in `FLASH_ACR`. With prefetch, the flash interface reads the next
+    /// double-word of the instruction stream while the current one executes.
+    ///
+    /// The ECC check happens when a double-word is read from the cells, so a prefetch of a
+    /// partially written location can raise the ECC NMI on its own, before (or without) the CPU
+    /// executing from there. Whether that happens depends on whether the prefetch reads the
+    /// location while it is being written or after the write settled, so results with and
+    /// without prefetch are not directly comparable.
+    pub fn set_prefetch_enabled(&mut self, enabled: bool) {
+        // Let outstanding flash reads finish with the old setting
+        dsb();
+        self.flash.acr.modify(|_, w| w.prften().bit(enabled));
+        // Instructions after this are fetched with the new setting
+        dsb();
+        isb();
+    }
+
+    /// Whether `PRFTEN` is set in `FLASH_ACR`, see [Flash::set_prefetch_enabled]
+    pub fn is_prefetch_enabled(&self) -> bool {
+        self.flash.acr.read().prften().bit_is_set()
+    }
+
+    /// Clears the ECC error flags (`ECCD` and `ECCC`), which also allows `FLASH_ECCR` to capture
+    /// the address of the next error
+    pub fn clear_ecc_flags(&mut self) {
+        // Both flags are cleared by writing 1
+        self.flash
+            .eccr
+            .modify(|_, w| w.eccd().set_bit().eccc().set_bit());
+    }
+
+    /// Reads the double-word at the given address and checks whether reading it caused an ECC
+    /// error, without crashing on it.
+    ///
+    /// A double bit error always raises an NMI, which cannot be masked. While probing, the NMI
+    /// handler only records the error (see [handle_ecc_nmi_during_probe]) and returns. The
+    /// single-bit correction interrupt (`ECCIE`) is disabled for the duration of the read.
+    pub fn try_read_dword(&self, address: u32) -> Result<u64, EccError> {
+        let eccie = self.flash.eccr.read().eccie().bit_is_set();
+        self.flash.eccr.modify(|_, w| w.eccie().clear_bit());
+        ECC_PROBE_ECCR.store(0, Ordering::SeqCst);
+        ECC_PROBE_ACTIVE.store(true, Ordering::SeqCst);
+
+        // SAFETY: Reading memory has no side effects besides the ECC check we are after. An
+        // address outside the flash ends in a bus fault, which the HardFault handler reports
+        let value = unsafe { core::ptr::read_volatile(address as *const u64) };
+
+        // Make sure a pending NMI is taken before we look at the result
+        dsb();
+        isb();
+        ECC_PROBE_ACTIVE.store(false, Ordering::SeqCst);
+
+        // Single bit errors don't cause an NMI, so they are still in the register
+        let eccr = self.flash.eccr.read().bits() | ECC_PROBE_ECCR.load(Ordering::SeqCst);
+        self.flash
+            .eccr
+            .modify(|_, w| w.eccd().set_bit().eccc().set_bit().eccie().bit(eccie));
+
+        let address = Flash::ecc_error_address(eccr);
+        if eccr & Flash::ECCR_ECCD != 0 {
+            Err(EccError::Uncorrectable { address })
+        } else if eccr & Flash::ECCR_ECCC != 0 {
+            Err(EccError::Corrected { address })
+        } else {
+            Ok(value)
+        }
+    }
+
+    /// Address of the error recorded in `FLASH_ECCR`: the offset within the bank, with
+    /// [Flash::ECC_ADDRESS_BANK2] set for bank 2
+    fn ecc_error_address(eccr: u32) -> u32 {
+        let bank2 = eccr & Flash::ECCR_BK_ECC != 0;
+        (eccr & Flash::ECCR_ADDR_MASK) | if bank2 { Flash::ECC_ADDRESS_BANK2 } else { 0 }
+    }
+
+    /// Converts an address from [EccError] into an offset in the physical flash layout, like the
+    /// targets. `BK_ECC` names the physical bank, the same way as `BKER` does for erasing (see
+    /// [FlashProgram::erase_page]), so a bank swap with BFB2 doesn't change the result; use
+    /// [Flash::bank_alias] to get the address the CPU sees. This doesn't need the [Flash]
+    /// instance, as a bank is always half of the flash when there are two.
+    pub fn ecc_address_to_offset(address: u32) -> u32 {
+        let offset = address & Flash::ECCR_ADDR_MASK;
+
+        if address & Flash::ECC_ADDRESS_BANK2 != 0 {
+            Flash::size_bytes() / 2 + offset
+        } else {
+            offset
+        }
+    }
+
+    /// Decodes the ECC error currently recorded in `FLASH_ECCR`, if there is one. The flash
+    /// doesn't know the timing, so `timing_middle` is 0 and has to be filled in by the caller.
+    /// A reset clears `FLASH_ECCR`, so this only helps before the watchdog reset, not after it.
+    #[allow(dead_code)]
+    pub fn decode_corruption_result(&self) -> Option<CorruptionResult> {
+        let eccr = self.flash.eccr.read().bits();
+        let address = Flash::ecc_error_address(eccr);
+
+        let error = if eccr & Flash::ECCR_ECCD != 0 {
+            EccError::Uncorrectable { address }
+        } else if eccr & Flash::ECCR_ECCC != 0 {
+            EccError::Corrected { address }
+        } else {
+            return None;
+        };
+
+        Some(CorruptionResult::from_ecc_error(error, 0))
+    }
+
+    /// Returns whether bank 2 is mapped to the start of the flash, according to the BFB2 bit in
+    /// the option register. Strictly speaking, BFB2 makes the bootloader set `FB_MODE` in
+    /// `SYSCFG_MEMRMP`, which is what swaps the banks; this assumes it did so at boot.
+    pub fn bank_swap_active(&self) -> bool {
+        let opts = self.option_bytes_read();
+        opts.dual_bank() && opts.bfb2()
+    }
+
+    /// Converts between an offset in the physical flash layout (bank 1 first) and the address
+    /// relative to the start of the flash as seen by the CPU. The two are the same unless the
+    /// banks are swapped, and since swapping is symmetric, this works in both directions.
+    pub fn bank_alias(&self, address: u32) -> u32 {
+        if !self.bank_swap_active() {
+            return address;
+        }
+
+        let bank_size = self.page_count() / 2 * self.page_size_bytes();
+        if address < bank_size {
+            address + bank_size
+        } else {
+            address - bank_size
+        }
+    }
+
+    /// Returns the physical page number for a given address as seen by the CPU, depending on the
+    /// [Flash::page_size_bytes]. With swapped banks, the pages of bank 2 come first in the address
+    /// space, but [FlashProgram::erase_page] still selects them as bank 2
     pub fn address_to_page_number(&self, address: u32) -> u32 {
-        address / self.page_size()
+        self.bank_alias(address) / self.page_size_bytes()
+    }
+
+    /// Returns the page containing the given address (relative to the start of the flash, as seen
+    /// by the CPU).
+    /// Fails with [Error::InvalidPage] if the address lies beyond the end of the flash.
+    pub fn make_page(&self, address: u32) -> Result<FlashPage, Error> {
+        let page_number = self.address_to_page_number(address);
+
+        // The number of pages depends on the device flash size and the bank mode
+        if page_number >= self.page_count() {
+            return Err(Error::InvalidPage);
+        }
+
+        Ok(FlashPage(page_number))
     }
 }
 
@@ -135,8 +785,253 @@ impl<'a> FlashUnlocked<'a> {
         });
     }
 
-    /// Erases the flash page with the given number.
-    pub fn erase_page(&mut self, page_number: u32) -> Result<(), Error> {
+    /// Checks that `dwords` double-words can be programmed starting at `address`
+    fn check_write_target(&self, address: *mut usize, dwords: usize) -> Result<(), Error> {
+        debug_assert_ne!(address, 0 as *mut usize, "attempt to write to 0");
+
+        check_dword_range(address as usize, dwords, Flash::size_bytes() as usize)
+    }
+
+    /// Erases a whole bank (1 or 2) with a single mass erase. In single-bank mode, only bank 1
+    /// exists and it covers the whole flash. Fails with [Error::InvalidPage] for any other bank.
+    ///
+    /// Be careful: this tool runs from the start of the flash, so erasing the bank that is mapped
+    /// there (bank 1, or bank 2 if [Flash::bank_swap_active]) erases the tool itself.
+    /// Not used by any [crate::strategy::CorruptStrategy], it is only here for research.
+    #[allow(dead_code)]
+    pub fn erase_bank(&mut self, bank: u8) -> Result<(), Error> {
+        // According to "3.3.6 Flash main memory erase sequences", "Bank 1 or bank 2 mass erase"
+        let mer = match bank {
+            1 => Flash::CR_MER1,
+            2 if self.is_dual_bank() => Flash::CR_MER2,
+            _ => return Err(Error::InvalidPage),
+        };
+
+        // 1. Check that no Flash memory operation is ongoing by checking the BSY bit in FLASH_SR
+        self.wait()?;
+
+        // 2. Check and clear all error programming flags due to a previous programming
+        self.clear_programming_flags();
+
+        // During proofs, we want to skip hardware interaction
+        #[cfg(kani)]
+        return Ok(());
+
+        // 3. Set the MER1 or MER2 bit
+        // SAFETY: Only the MER bit of the selected bank is changed
+        self.flash
+            .flash
+            .cr
+            .modify(|r, w| unsafe { w.bits(r.bits() | mer) });
+
+        // 4. Set the STRT bit in the FLASH_CR register
+        self.flash.flash.cr.modify(|_, w| w.start().set_bit());
+
+        // 5. Wait for the BSY bit to be cleared in the FLASH_SR register
+        let result = self.wait_timeout(Flash::BANK_ERASE_TIMEOUT_MS);
+
+        // SAFETY: Only the MER bit of the selected bank is changed
+        self.flash
+            .flash
+            .cr
+            .modify(|r, w| unsafe { w.bits(r.bits() & !mer) });
+
+        result
+    }
+
+    /// Writes the given option bytes according to "3.4.2 Option bytes programming".
+    ///
+    /// The option bytes have their own lock (`OPTLOCK`), which can only be unlocked while
+    /// `FLASH_CR` itself is unlocked. After programming, the option lock is set again.
+    /// Note that the new values are only loaded after an option byte reload
+    /// ([FlashUnlocked::reload_option_bytes]) or a power-on reset; until then,
+    /// [Flash::option_bytes_read] returns the old values.
+    pub fn option_bytes_write(&mut self, opts: OptionBytes) -> Result<(), Error> {
+        self.program_option_bytes(|flash| {
+            // SAFETY: Every value is accepted, the caller is responsible for what it means (see
+            // the warnings on OptionBytes)
+            flash.optr.write(|w| unsafe { w.bits(opts.raw) });
+        })
+    }
+
+    /// Sets the PCROP area to the pages `start_page..=end_page` of bank 1. With `rdp_prot`, the
+    /// area is erased when the read protection is lowered from level 1 to 0; without it, it stays.
+    /// Like [FlashUnlocked::option_bytes_write], this only takes effect after an option byte
+    /// reload.
+    ///
+    /// Careful: once set, PCROP can't simply be disabled again. Shrinking the area or setting
+    /// `PCROP1_STRT > PCROP1_END` is only accepted together with a regression from RDP level 1 to
+    /// 0, i.e. the RDP level has to be raised and then lowered again, which mass-erases the flash.
+    /// The same goes for clearing `PCROP_RDP`.
+    pub fn pcrop_set_region(
+        &mut self,
+        start_page: u32,
+        end_page: u32,
+        rdp_prot: bool,
+    ) -> Result<(), Error> {
+        // PCROP area 1 only covers bank 1, which is all of the flash in single-bank mode
+        let bank1_pages = if self.is_dual_bank() {
+            self.page_count() / 2
+        } else {
+            self.page_count()
+        };
+        if start_page > end_page || end_page >= bank1_pages {
+            return Err(Error::InvalidPage);
+        }
+
+        let dwords_per_page = self.page_size_bytes() / Flash::PCROP_GRANULARITY;
+        let start = start_page * dwords_per_page;
+        let end = (end_page + 1) * dwords_per_page - 1;
+
+        self.program_option_bytes(|flash| {
+            // SAFETY: Both offsets were checked to be within bank 1 above
+            flash
+                .pcrop1sr
+                .modify(|_, w| unsafe { w.pcrop1_strt().bits(start as u16) });
+            flash.pcrop1er.modify(|_, w| unsafe {
+                w.pcrop1_end().bits(end as u16).pcrop_rdp().bit(rdp_prot)
+            });
+        })
+    }
+
+    /// Loads the option bytes written by [FlashUnlocked::option_bytes_write] and
+    /// [FlashUnlocked::pcrop_set_region] by setting `OBL_LAUNCH`. This resets the MCU, so it only
+    /// returns if the option bytes couldn't be unlocked.
+    pub fn reload_option_bytes(&mut self) -> Result<(), Error> {
+        self.wait()?;
+        self.unlock_option_bytes()?;
+
+        self.flash.flash.cr.modify(|_, w| w.obl_launch().set_bit());
+
+        // The reset happens while the option bytes are loaded
+        loop {
+            cortex_m::asm::nop();
+        }
+    }
+
+    /// Unlocks the option bytes by writing the two keys to `FLASH_OPTKEYR`
+    fn unlock_option_bytes(&mut self) -> Result<(), Error> {
+        self.write_option_key(Flash::OPT_KEY1);
+        self.write_option_key(Flash::OPT_KEY2);
+
+        // Like the LOCK bit, OPTLOCK stays set until the next reset if the sequence was wrong
+        if self.flash.flash.cr.read().optlock().bit_is_set() {
+            return Err(Error::UnlockFailed);
+        }
+
+        Ok(())
+    }
+
+    /// Option byte programming sequence around `write`, which changes the option registers
+    fn program_option_bytes<F>(&mut self, write: F) -> Result<(), Error>
+    where
+        F: FnOnce(&pac::FLASH),
+    {
+        // 1. Check that no Flash memory operation is ongoing
+        self.wait()?;
+
+        self.unlock_option_bytes()?;
+        self.clear_programming_flags();
+        // OPTVERR may still be set from the last option byte load, it is cleared by writing 1
+        self.flash.flash.sr.modify(|_, w| w.optverr().set_bit());
+
+        // 2. Write the desired option value in the options registers
+        write(&self.flash.flash);
+
+        // 3. Set the Options Start bit OPTSTRT in the Flash control register
+        self.flash.flash.cr.modify(|_, w| w.optstrt().set_bit());
+
+        // 4. Wait for the BSY bit to be cleared
+        let result = self.wait().and_then(|()| {
+            if self.flash.flash.sr.read().optverr().bit_is_set() {
+                Err(Error::OptionValidity)
+            } else {
+                Ok(())
+            }
+        });
+
+        // Lock the option bytes again, the normal flash lock is handled by the destructor
+        self.flash.flash.cr.modify(|_, w| w.optlock().set_bit());
+
+        result
+    }
+
+    /// Wait until the busy bit of the flash status register is cleared.
+    /// This must be done e.g. during writes.
+    pub fn wait(&mut self) -> Result<(), Error> {
+        //! XXX: The datasheet for the STM32L4R5xx says, that max(t_prog) = 90.8µs and that
+        //! max(t_prog_row normal programming) = 5.5ms, max(t_ERASE) = 24.5ms  and max(t_ME) = 25ms
+        //!
+        //! The longest operation this is used for is a page erase, so the maximum amount of time
+        //! we should wait is 25ms, or in clock cycles at the reset value of 4MHz
+        //! 25ms*4MHz = 100_000. The loop count scales with the clock passed to
+        //! [Flash::with_sysclk_hz]
+        //!
+        //! A bank erase (t_ME) uses [FlashUnlocked::wait_timeout] with a longer timeout instead
+        //!
+        //! A single loop will take longer than 1 clock cycle, so we are actually waiting for much
+        //! longer, i.e. at 4 clock cycles per loop iteration we would actually wait a maximum of
+        //! 100ms
+        //!
+        //! A timeout is indicated by returning Error::Busy
+
+        self.wait_timeout(Flash::OPERATION_TIMEOUT_MS)
+    }
+
+    /// Like [FlashUnlocked::wait], with the timeout given in milliseconds
+    fn wait_timeout(&mut self, timeout_ms: u32) -> Result<(), Error> {
+        for _ in 0..self.flash.sysclk_hz / 1_000 * timeout_ms {
+            if self.flash.flash.sr.read().bsy().bit_is_clear() {
+                break;
+            }
+        }
+
+        self.status()
+    }
+}
+
+/// The flash operations an attempt is made of. Implemented by [FlashUnlocked] and, for host
+/// tests, by [mock::MockFlashUnlocked], so that e.g. [crate::strategy::execute_write] can be tested
+/// without hardware.
+pub trait FlashProgram {
+    /// Erases the given page
+    fn erase_page(&mut self, page: FlashPage) -> Result<(), Error>;
+
+    /// Writes double-words to erased flash at an 8 byte aligned address
+    fn write_dwords(&mut self, address: *mut usize, array: &[u64]) -> Result<(), Error>;
+
+    /// Like [FlashProgram::write_dwords], but expects the flash not to be erased. The hardware
+    /// then fails with [Error::ProgrammingSequence]
+    fn write_dwords_no_erase_check(
+        &mut self,
+        address: *mut usize,
+        array: &[u64],
+    ) -> Result<(), Error>;
+
+    /// Like [FlashProgram::write_dwords], but the double-words are copied by `dma` channel
+    /// `channel`. Fails with [Error::InvalidChannel] unless `channel` is 1 to 7
+    fn write_dwords_dma(
+        &mut self,
+        dma: &mut pac::DMA1,
+        channel: u8,
+        address: *mut usize,
+        array: &[u64],
+    ) -> Result<(), Error>;
+
+    /// Writes a single 32-bit word instead of a double-word. This is **intentionally erroneous**
+    /// and only meant for fault-injection research, to test how firmware handles the resulting
+    /// programming error flags. Returns [Error::Illegal] when the write is rejected as expected,
+    /// and [Error::NotRejected] if it went through without an error flag
+    fn write_word_illegal(&mut self, address: *mut u32, value: u32) -> Result<(), Error>;
+}
+
+impl<'a> FlashProgram for FlashUnlocked<'a> {
+    /// Erases the given flash page.
+    ///
+    /// An erase can't be suspended on the STM32L4: `FLASH_CR` has no `SUSPEND` bit and `FLASH_SR`
+    /// no `PESD` flag (those exist on e.g. the STM32WB). Reads from the flash just stall until the
+    /// erase is done, so the only way to interrupt it is a reset.
+    fn erase_page(&mut self, page: FlashPage) -> Result<(), Error> {
         // According to "3.3.6 Flash main memory erase sequences"
 
         // 1. Check that no Flash memory operation is ongoing by checking the BSY bit in FLASH_SR
@@ -145,15 +1040,21 @@ impl<'a> FlashUnlocked<'a> {
         // 2. Check and clear all error programming flags due to a previous programming. If not, PGSERR is set
         self.clear_programming_flags();
 
-        // Single-Bank mode, we have 256 pages with size 0x800 bytes
-        if page_number >= 256 {
-            return Err(Error::InvalidPage);
+        let page_number = page.number();
+
+        // PNB only selects the page within a bank
+        let (second_bank, page_in_bank) = self.page_in_bank(page_number);
+
+        // Erasing a write-protected page would only set WRPERR without erasing anything
+        if self.is_page_write_protected(page_number) {
+            return Err(Error::WriteProtection);
         }
 
         // During proofs, we want to skip hardware interaction
         #[cfg(kani)]
         return Ok(());
 
+        // SAFETY: page_in_bank is less than the number of pages in a bank, which fits into PNB
         self.flash.flash.cr.modify(|_, w| unsafe {
             w
                 // Set the PER bit
@@ -161,10 +1062,10 @@ impl<'a> FlashUnlocked<'a> {
                 .set_bit()
                 // Select the page to erase
                 .pnb()
-                .bits(page_number as u8)
-                // The BKER bit [...] must be kept cleared
+                .bits(page_in_bank as u8)
+                // Select the bank the page is in. In single-bank mode, this is always cleared
                 .bker()
-                .clear_bit()
+                .bit(second_bank)
         });
 
         // 4. Set the STRT bit in the FLASH_CR register
@@ -184,11 +1085,13 @@ impl<'a> FlashUnlocked<'a> {
     /// This must only be called when the following is true:
     /// - The flash is unlocked
     /// - The target page(s) have been erased before
-    pub fn write_dwords(&mut self, mut address: *mut usize, array: &[u64]) -> Result<(), Error> {
+    /// - `address` is 8 byte aligned, as the flash is programmed in double-words. Otherwise,
+    ///   [Error::ProgrammingAlignment] is returned before anything is written
+    fn write_dwords(&mut self, address: *mut usize, array: &[u64]) -> Result<(), Error> {
         // See reference manual, "3.3.7 Flash main memory programming sequences"
         // We do "Standard programming"
 
-        debug_assert_ne!(address, 0 as *mut usize, "attempt to write to 0");
+        self.check_write_target(address, array.len())?;
 
         // 1. Check that no Flash main memory operation is ongoing
         self.wait()?;
@@ -200,13 +1103,9 @@ impl<'a> FlashUnlocked<'a> {
         self.flash.flash.cr.modify(|_, w| w.pg().set_bit());
 
         // 4. Perform the data write operation at the desired memory address, inside main memory block or OTP area
-        for dword in array {
-            unsafe {
-                core::ptr::write_volatile(address, *dword as usize);
-                dmb();
-                core::ptr::write_volatile(address.add(1), (*dword >> 32) as usize);
-                address = address.add(2);
-            }
+        for (i, dword) in array.iter().enumerate() {
+            // SAFETY: The whole range was checked to be in the flash, and PG is set
+            unsafe { program_dword(dword_address(address as usize, i), *dword) };
 
             // 5. Wait until the BSY bit is cleared in the FLASH_SR register
             self.wait()?;
@@ -228,27 +1127,298 @@ impl<'a> FlashUnlocked<'a> {
         Ok(())
     }
 
-    /// Wait until the busy bit of the flash status register is cleared.
-    /// This must be done e.g. during writes.
-    pub fn wait(&mut self) -> Result<(), Error> {
-        //! XXX: The datasheet for the STM32L4R5xx says, that max(t_prog) = 90.8µs and that
-        //! max(t_prog_row normal programming) = 5.5ms, max(t_ERASE) = 24.5ms  and max(t_ME) = 25ms
-        //!
-        //! We assume that the system clock is configured to the reset value of 4MHz and there for
-        //! the maximum amount of time we should wait is 25ms, or in clock cycles 25ms/4MHz = 100_000
-        //!
-        //! A single loop will take longer than 1 clock cycle, so we are actually waiting for much
-        //! longer, i.e. at 4 clock cycles per loop iteration we would actually wait a maximum of
-        //! 100ms
-        //!
-        //! A timeout is indicated by returning Error::Busy
+    /// Like [FlashProgram::write_dwords], but meant for targets that have **not** been erased
+    /// before, to reproduce firmware that forgets to erase.
+    ///
+    /// Programming a double-word that is not all ones sets `PROGERR` in hardware, which is
+    /// reported as [Error::ProgrammingSequence] instead of the generic [Error::Illegal].
+    fn write_dwords_no_erase_check(
+        &mut self,
+        address: *mut usize,
+        array: &[u64],
+    ) -> Result<(), Error> {
+        let result = self.write_dwords(address, array);
 
-        for _ in 0..100_000 {
-            if self.flash.flash.sr.read().bsy().bit_is_clear() {
-                break;
+        if self.flash.flash.sr.read().progerr().bit_is_set() {
+            // write_dwords stopped early, so PG is still set
+            self.flash.flash.cr.modify(|_, w| w.pg().clear_bit());
+            return Err(Error::ProgrammingSequence);
+        }
+
+        result
+    }
+
+    /// Like [FlashProgram::write_dwords], but the words are written by DMA1 `channel` (1 to 7)
+    /// in memory-to-memory mode instead of by the CPU. The write then doesn't happen at a fixed
+    /// number of cycles after the call, as it depends on when the DMA gets the bus, and the CPU
+    /// is busy polling the DMA flags while it happens.
+    ///
+    /// DMA1 has to be clocked (`DMA1EN` in `RCC_AHB1ENR`) before calling this, otherwise the
+    /// transfer never starts and [Error::Busy] is returned.
+    fn write_dwords_dma(
+        &mut self,
+        dma: &mut pac::DMA1,
+        channel: u8,
+        address: *mut usize,
+        array: &[u64],
+    ) -> Result<(), Error> {
+        if !(1..=7).contains(&channel) {
+            return Err(Error::InvalidChannel);
+        }
+        self.check_write_target(address, array.len())?;
+        let timeout_loops = self.flash.sysclk_hz / 1_000 * Flash::OPERATION_TIMEOUT_MS;
+
+        // Same sequence as write_dwords, see there
+        self.wait()?;
+        self.clear_programming_flags();
+        self.flash.flash.cr.modify(|_, w| w.pg().set_bit());
+
+        for (i, dword) in array.iter().enumerate() {
+            // The DMA reads from RAM, even if `array` is in the flash
+            let [(target, low), (_, high)] =
+                dword_words(dword_address(address as usize, i), *dword);
+            let words = [low, high];
+
+            // SAFETY: The whole range was checked to be in the flash and PG is set, so the DMA
+            // programs the flash. `words` outlives the transfer, which is waited for
+            let result = unsafe {
+                dma_transfer_words(dma, channel, target as *mut usize, &words, timeout_loops)
+            };
+            if let Err(e) = result {
+                self.flash.flash.cr.modify(|_, w| w.pg().clear_bit());
+                return Err(e);
+            }
+
+            self.wait()?;
+            if self.flash.flash.sr.read().eop().bit_is_set() {
+                self.flash.flash.sr.modify(|_, w| w.eop().clear_bit());
             }
         }
 
-        self.status()
+        self.flash.flash.cr.modify(|_, w| w.pg().clear_bit());
+
+        Ok(())
+    }
+
+    /// Sets `PG` and writes a single word. Only a full double-word is a valid programming access,
+    /// so the controller flags this with `SIZERR`. If it waits for the second word instead,
+    /// clearing `PG` with only one word written ends the sequence with `PGSERR`; either flag is
+    /// reported as [Error::Illegal].
+    fn write_word_illegal(&mut self, address: *mut u32, value: u32) -> Result<(), Error> {
+        debug_assert_ne!(address, 0 as *mut u32, "attempt to write to 0");
+
+        if !self.contains(address as usize, core::mem::size_of::<u32>()) {
+            return Err(Error::InvalidPage);
+        }
+
+        // Same preparation as for a normal write
+        self.wait()?;
+        self.clear_programming_flags();
+        self.flash.flash.cr.modify(|_, w| w.pg().set_bit());
+
+        // SAFETY: The address was checked to be in the flash, and PG is set. At most one word of
+        // the double-word gets programmed
+        unsafe {
+            core::ptr::write_volatile(address, value);
+        }
+        dmb();
+
+        let result = self.wait();
+        self.flash.flash.cr.modify(|_, w| w.pg().clear_bit());
+
+        let sr = self.flash.flash.sr.read();
+        if sr.sizerr().bit_is_set() || sr.pgserr().bit_is_set() {
+            return Err(Error::Illegal);
+        }
+
+        result.and(Err(Error::NotRejected))
+    }
+}
+
+/// Whether the `len` bytes starting at `address` lie within a flash of `flash_size` bytes, either
+/// at [crate::hw::FLASH_BASE] or in the alias at address 0 that we boot from
+fn range_in_flash(address: usize, len: usize, flash_size: usize) -> bool {
+    let base = crate::hw::FLASH_BASE as usize;
+    let offset = if address >= base {
+        address - base
+    } else {
+        address
+    };
+
+    offset
+        .checked_add(len)
+        .map_or(false, |end| end <= flash_size)
+}
+
+/// Checks that `dwords` double-words starting at `address` are aligned and lie within a flash of
+/// `flash_size` bytes, so that every address [dword_address] returns for them can be programmed
+fn check_dword_range(address: usize, dwords: usize, flash_size: usize) -> Result<(), Error> {
+    // The hardware would only set PGAERR, which we'd see after the first double-word
+    if address % DWORD_BYTES != 0 {
+        return Err(Error::ProgrammingAlignment);
+    }
+
+    // Writing anywhere else would not program the flash, but overwrite e.g. RAM
+    let in_flash = dwords
+        .checked_mul(DWORD_BYTES)
+        .map_or(false, |len| range_in_flash(address, len, flash_size));
+    if !in_flash {
+        return Err(Error::InvalidPage);
+    }
+
+    Ok(())
+}
+
+const DWORD_BYTES: usize = core::mem::size_of::<u64>();
+
+/// Address of the `index`th double-word of a write starting at `start`
+fn dword_address(start: usize, index: usize) -> usize {
+    start + index * DWORD_BYTES
+}
+
+/// The two word writes that program `dword` at `address`, as `(address, value)` in the order
+/// they are done: low word first
+fn dword_words(address: usize, dword: u64) -> [(usize, u32); 2] {
+    [
+        (address, dword as u32),
+        (address + DWORD_BYTES / 2, (dword >> 32) as u32),
+    ]
+}
+
+/// Programs one double-word as the two word writes of [dword_words].
+///
+/// # Safety
+///
+/// `address` must be 8 byte aligned and point into the flash, and the flash must be unlocked with
+/// `PG` set. Then the writes program the flash instead of changing any memory Rust knows about.
+unsafe fn program_dword(address: usize, dword: u64) {
+    let [(low_address, low), (high_address, high)] = dword_words(address, dword);
+
+    // SAFETY: Guaranteed by the caller
+    unsafe {
+        core::ptr::write_volatile(low_address as *mut u32, low);
+        dmb();
+        core::ptr::write_volatile(high_address as *mut u32, high);
+    }
+}
+
+/// Offset of the registers of DMA channel 1, the other channels follow every 0x14 bytes
+const DMA_CHANNEL_OFFSET: usize = 0x08;
+const DMA_CHANNEL_STRIDE: usize = 0x14;
+const DMA_CCR_OFFSET: usize = 0x00;
+const DMA_CNDTR_OFFSET: usize = 0x04;
+const DMA_CPAR_OFFSET: usize = 0x08;
+const DMA_CMAR_OFFSET: usize = 0x0C;
+/// `DMA_CCRx`: EN, DIR (read from CMAR), PINC, MINC, PSIZE and MSIZE 32 bit, MEM2MEM
+const DMA_CCR_MEM_TO_FLASH: u32 = 1 | 1 << 4 | 1 << 6 | 1 << 7 | 0b10 << 8 | 0b10 << 10 | 1 << 14;
+/// `TCIF` and `TEIF` in `DMA_ISR`, shifted by 4 bits per channel
+const DMA_ISR_TCIF: u32 = 1 << 1;
+const DMA_ISR_TEIF: u32 = 1 << 3;
+/// `CGIF` in `DMA_IFCR` clears all flags of a channel
+const DMA_IFCR_CGIF: u32 = 1 << 0;
+
+/// Copies `words` to `address` with DMA1 `channel` and polls up to `timeout_loops` times for the
+/// transfer to finish. The channels are separate fields in the PAC, so the channel registers are
+/// accessed by address.
+///
+/// # Safety
+///
+/// Same as [program_dword] for `address`, and the channel must not be in use.
+unsafe fn dma_transfer_words(
+    dma: &mut pac::DMA1,
+    channel: u8,
+    address: *mut usize,
+    words: &[u32; 2],
+    timeout_loops: u32,
+) -> Result<(), Error> {
+    let base = pac::DMA1::ptr() as usize
+        + DMA_CHANNEL_OFFSET
+        + DMA_CHANNEL_STRIDE * (channel as usize - 1);
+    let register = |offset| (base + offset) as *mut u32;
+    let flag_shift = 4 * (channel as u32 - 1);
+
+    // SAFETY: The registers of the given channel, which the caller guarantees to be unused.
+    // With PG set, the transfer programs the flash as in program_dword
+    unsafe {
+        core::ptr::write_volatile(register(DMA_CCR_OFFSET), 0);
+        dma.ifcr.write(|w| w.bits(DMA_IFCR_CGIF << flag_shift));
+
+        core::ptr::write_volatile(register(DMA_CNDTR_OFFSET), words.len() as u32);
+        core::ptr::write_volatile(register(DMA_CPAR_OFFSET), address as u32);
+        core::ptr::write_volatile(register(DMA_CMAR_OFFSET), words.as_ptr() as u32);
+
+        // `words` has to be in RAM before the DMA reads it
+        dmb();
+        core::ptr::write_volatile(register(DMA_CCR_OFFSET), DMA_CCR_MEM_TO_FLASH);
+    }
+
+    // A transfer of two words takes a few cycles, unless DMA1 is not clocked
+    let mut result = Err(Error::Busy);
+    for _ in 0..timeout_loops {
+        let isr = dma.isr.read().bits() >> flag_shift;
+        if isr & DMA_ISR_TEIF != 0 {
+            result = Err(Error::DmaTransfer);
+            break;
+        }
+        if isr & DMA_ISR_TCIF != 0 {
+            result = Ok(());
+            break;
+        }
+    }
+
+    // SAFETY: As above
+    unsafe {
+        core::ptr::write_volatile(register(DMA_CCR_OFFSET), 0);
+        dma.ifcr.write(|w| w.bits(DMA_IFCR_CGIF << flag_shift));
+    }
+
+    result
+}
+
+#[cfg(kani)]
+mod verification {
+    use super::*;
+
+    /// Runs the address stepping of [FlashProgram::write_dwords] and [program_dword] on a write
+    /// that [check_dword_range] accepted, and checks that every word write lands in the flash,
+    /// aligned, in its own slot, and that the two words of each double-word hold its value
+    #[kani::proof]
+    #[kani::unwind(5)]
+    fn write_dwords_steps_address_correctly() {
+        let start: usize = kani::any();
+        let flash_size: usize = kani::any();
+        let array: [u64; 4] = kani::any();
+        let dwords: usize = kani::any();
+        kani::assume(dwords <= array.len());
+        // The largest STM32L4 flash is 2 MB, far below the FLASH_BASE alias boundary
+        kani::assume(flash_size <= 2 * 1024 * 1024);
+
+        if check_dword_range(start, dwords, flash_size).is_err() {
+            return;
+        }
+
+        for (i, dword) in array[..dwords].iter().enumerate() {
+            let address = dword_address(start, i);
+            let [(low_address, low), (high_address, high)] = dword_words(address, *dword);
+
+            assert_eq!(address, start + i * 8);
+            assert_eq!(low_address % 8, 0);
+            assert_eq!(high_address, low_address + 4);
+            assert!(range_in_flash(low_address, 8, flash_size));
+            assert_eq!(((high as u64) << 32) | low as u64, *dword);
+        }
+    }
+
+    #[kani::proof]
+    fn unaligned_writes_are_rejected() {
+        let start: usize = kani::any();
+        let dwords: usize = kani::any();
+        let flash_size: usize = kani::any();
+        kani::assume(start % 8 != 0);
+
+        assert_eq!(
+            check_dword_range(start, dwords, flash_size),
+            Err(Error::ProgrammingAlignment)
+        );
     }
 }
@@ -0,0 +1,136 @@
+//! Decoding of single bit errors with a SEC-DED Hamming code over 64 data bits and 8 check bits,
+//! the same shape as the flash ECC of the STM32L4.
+//!
+//! Note that this is a model, not a description of the silicon: the reference manual only says
+//! that 8 ECC bits are stored per double-word, but ST does not publish the parity check matrix,
+//! and the check bits can't be read back by software. This module uses the textbook extended
+//! Hamming code instead:
+//!
+//! * Codeword positions 1 to 71 hold the 64 data bits (in order, skipping the powers of two)
+//!   and check bits 0 to 6 (at positions 1, 2, 4, ..., 64). Check bit `i` is the parity of all
+//!   data bits whose position has bit `i` set.
+//! * Check bit 7 is the parity over all data bits and check bits 0 to 6, so that the whole
+//!   codeword has even parity. This is what tells single and double errors apart.
+//!
+//! The results are therefore only meaningful together with check bits computed by
+//! [compute_check_bits], e.g. when replaying a dump in a simulation. Bit positions are numbered
+//! 0 to 63 for the data bits (bit 0 is the LSB of the low word) and 64 to 71 for check bits 0
+//! to 7.
+
+/// Codeword position of each data bit
+const DATA_POSITIONS: [u8; 64] = {
+    let mut positions = [0; 64];
+    let mut position: u8 = 1;
+    let mut bit = 0;
+    while bit < 64 {
+        if !position.is_power_of_two() {
+            positions[bit] = position;
+            bit += 1;
+        }
+        position += 1;
+    }
+    positions
+};
+
+/// Bit 7 of the syndrome, set if the overall parity is wrong
+const SYNDROME_PARITY: u8 = 1 << 7;
+/// Bits 0 to 6 of the syndrome, the codeword position of a single bit error
+const SYNDROME_POSITION_MASK: u8 = 0x7F;
+
+/// Computes the 8 check bits that are stored for `data`
+pub fn compute_check_bits(data: u64) -> u8 {
+    let mut hamming = 0;
+    for (bit, position) in DATA_POSITIONS.iter().enumerate() {
+        if data & (1 << bit) != 0 {
+            hamming ^= position;
+        }
+    }
+
+    let parity = (data.count_ones() + hamming.count_ones()) as u8 & 1;
+    hamming | parity << 7
+}
+
+/// Computes the syndrome of a double-word and its stored check bits. Bits 0 to 6 are the
+/// codeword position of the flipped bit and bit 7 is set if the overall parity is wrong.
+/// A syndrome of 0 means that no error was detected.
+pub fn compute_syndrome(data_low: u32, data_high: u32, check_bits: u8) -> u8 {
+    let data = (data_high as u64) << 32 | data_low as u64;
+
+    let hamming = (compute_check_bits(data) ^ check_bits) & SYNDROME_POSITION_MASK;
+    let parity = (data.count_ones() + check_bits.count_ones()) as u8 & 1;
+    hamming | parity << 7
+}
+
+/// Converts a syndrome from [compute_syndrome] to the position of the flipped bit, see the
+/// module documentation for the numbering. Returns `None` if there is no error or if more than one
+/// bit was flipped, as those can't be located.
+pub fn syndrome_to_bit_position(syndrome: u8) -> Option<u8> {
+    let position = syndrome & SYNDROME_POSITION_MASK;
+
+    // A single bit error always breaks the overall parity, a double bit error never does
+    if syndrome & SYNDROME_PARITY == 0 {
+        return None;
+    }
+
+    if position == 0 {
+        // Only the overall parity bit itself is wrong
+        return Some(71);
+    }
+
+    if position.is_power_of_two() {
+        return Some(64 + position.trailing_zeros() as u8);
+    }
+
+    // Positions above 71 don't exist, so this takes at least three flipped bits
+    DATA_POSITIONS
+        .iter()
+        .position(|&p| p == position)
+        .map(|bit| bit as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA: u64 = 0xDEAD_BEEF_0123_4567;
+
+    /// Flips the given bit of a codeword and returns the syndrome
+    fn syndrome_with_flipped(bits: &[u8]) -> u8 {
+        let mut data = DATA;
+        let mut check_bits = compute_check_bits(DATA);
+        for &bit in bits {
+            if bit < 64 {
+                data ^= 1 << bit;
+            } else {
+                check_bits ^= 1 << (bit - 64);
+            }
+        }
+
+        compute_syndrome(data as u32, (data >> 32) as u32, check_bits)
+    }
+
+    #[test]
+    fn no_error_has_zero_syndrome() {
+        assert_eq!(syndrome_with_flipped(&[]), 0);
+        assert_eq!(syndrome_to_bit_position(0), None);
+    }
+
+    #[test]
+    fn single_bit_errors_are_located() {
+        for bit in 0..72 {
+            let syndrome = syndrome_with_flipped(&[bit]);
+            assert_eq!(syndrome_to_bit_position(syndrome), Some(bit), "bit {}", bit);
+        }
+    }
+
+    #[test]
+    fn double_bit_errors_are_not_located() {
+        for first in 0..72 {
+            for second in first + 1..72 {
+                let syndrome = syndrome_with_flipped(&[first, second]);
+                assert_ne!(syndrome, 0);
+                assert_eq!(syndrome_to_bit_position(syndrome), None);
+            }
+        }
+    }
+}
@@ -0,0 +1,114 @@
+//! Calculation of double-words that read back with a chosen ECC error, based on the code model
+//! in [super::ecc]. Like that module, this is only as accurate as the model: on real silicon,
+//! the check bits are computed by the flash interface and can't be written directly.
+//!
+//! Bit positions and syndromes use the numbering of [super::ecc].
+
+use super::ecc::{compute_check_bits, compute_syndrome};
+
+/// Encodes `data` so that the codeword has a single bit error at `bit_position` (0 to 71).
+/// The data bits are returned unchanged, the error is put into the check bits: for a data bit,
+/// they are the check bits of `data` with that bit flipped, so the decoder "corrects" `data` into
+/// the flipped value. For a check bit, that bit is flipped.
+///
+/// Returns `(data_low, data_high, check_bits)`.
+pub fn encode_with_bit_flip(data: u64, bit_position: u8) -> (u32, u32, u8) {
+    assert!(bit_position < 72);
+
+    let check_bits = if bit_position < 64 {
+        compute_check_bits(data ^ 1 << bit_position)
+    } else {
+        compute_check_bits(data) ^ 1 << (bit_position - 64)
+    };
+
+    (data as u32, (data >> 32) as u32, check_bits)
+}
+
+/// Finds the data value closest to `current_data` (by Hamming distance) that has the syndrome
+/// `target_syndrome` when read back with the check bits that were stored for `current_data`.
+/// This is what a write that only partially reaches the cells has to look like to produce a
+/// specific ECC error.
+///
+/// Every syndrome can be reached by flipping at most three data bits. Among candidates with the
+/// same distance, the one with the lowest flipped bits is returned.
+pub fn data_to_flip_for_ecc_error(target_syndrome: u8, current_data: u64) -> u64 {
+    let check_bits = compute_check_bits(current_data);
+    let syndrome_of = |flips: u64| {
+        let data = current_data ^ flips;
+        compute_syndrome(data as u32, (data >> 32) as u32, check_bits)
+    };
+
+    if target_syndrome == 0 {
+        return current_data;
+    }
+
+    for first in 0..64 {
+        let one = 1 << first;
+        if syndrome_of(one) == target_syndrome {
+            return current_data ^ one;
+        }
+    }
+
+    for first in 0..64 {
+        for second in first + 1..64 {
+            let two = 1 << first | 1 << second;
+            if syndrome_of(two) == target_syndrome {
+                return current_data ^ two;
+            }
+        }
+    }
+
+    for first in 0..64 {
+        for second in first + 1..64 {
+            for third in second + 1..64 {
+                let three = 1 << first | 1 << second | 1 << third;
+                if syndrome_of(three) == target_syndrome {
+                    return current_data ^ three;
+                }
+            }
+        }
+    }
+
+    unreachable!("every syndrome is reachable with three bit flips")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::ecc::syndrome_to_bit_position;
+    use super::*;
+
+    const DATA: u64 = 0xDEAD_BEEF_0123_4567;
+
+    #[test]
+    fn bit_flip_is_located_at_the_requested_position() {
+        for bit in 0..72 {
+            let (low, high, check_bits) = encode_with_bit_flip(DATA, bit);
+            assert_eq!((high as u64) << 32 | low as u64, DATA);
+
+            let syndrome = compute_syndrome(low, high, check_bits);
+            assert_eq!(syndrome_to_bit_position(syndrome), Some(bit), "bit {}", bit);
+        }
+    }
+
+    #[test]
+    fn every_syndrome_is_reached() {
+        let check_bits = compute_check_bits(DATA);
+
+        for target in 0..=255 {
+            let data = data_to_flip_for_ecc_error(target, DATA);
+            let syndrome = compute_syndrome(data as u32, (data >> 32) as u32, check_bits);
+            assert_eq!(syndrome, target, "syndrome {:#x}", target);
+        }
+    }
+
+    #[test]
+    fn single_data_bit_syndromes_need_one_flip() {
+        for bit in 0..64 {
+            let (low, high, check_bits) = encode_with_bit_flip(DATA, bit);
+            let target = compute_syndrome(low, high, check_bits);
+
+            let data = data_to_flip_for_ecc_error(target, DATA);
+            assert_eq!(data, DATA ^ 1 << bit);
+        }
+    }
+}
@@ -0,0 +1,229 @@
+//! Host-side stand-in for [Flash](super::Flash) and [FlashUnlocked](super::FlashUnlocked), so that
+//! logic built on top of the flash can be tested with `cargo test` without hardware.
+//!
+//! The mock implements [FlashProgram] like [FlashUnlocked](super::FlashUnlocked) and follows the
+//! same rules as the hardware: double-words can only be written to erased (all 0xFF) memory and must be 8 byte aligned.
+//! Addresses are offsets into [MockFlash::memory].
+
+use super::{Error, FlashPage, FlashProgram};
+use crate::pac;
+
+/// Simulated flash memory
+pub struct MockFlash {
+    pub memory: [u8; 65536],
+    pub locked: bool,
+    /// Sticky error like the flags in `FLASH_SR`, until the next operation clears it
+    error: Option<Error>,
+}
+
+/// Unlocked simulated flash, relocks when dropped
+pub struct MockFlashUnlocked<'a> {
+    flash: &'a mut MockFlash,
+}
+
+impl<'a> Drop for MockFlashUnlocked<'a> {
+    fn drop(&mut self) {
+        self.flash.locked = true;
+    }
+}
+
+impl MockFlash {
+    /// Creates a locked, fully erased flash
+    pub fn new() -> Self {
+        MockFlash {
+            memory: [0xFF; 65536],
+            locked: true,
+            error: None,
+        }
+    }
+
+    pub fn page_size_bytes(&self) -> u32 {
+        0x800
+    }
+
+    pub fn page_count(&self) -> u32 {
+        self.memory.len() as u32 / self.page_size_bytes()
+    }
+
+    pub fn make_page(&self, address: u32) -> Result<FlashPage, Error> {
+        let page_number = address / self.page_size_bytes();
+        if page_number >= self.page_count() {
+            return Err(Error::InvalidPage);
+        }
+
+        Ok(FlashPage(page_number))
+    }
+
+    pub fn status(&self) -> Result<(), Error> {
+        match self.error {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    /// Like the hardware, unlocking flash that is not locked fails
+    pub fn unlock(&mut self) -> Result<MockFlashUnlocked, Error> {
+        if !self.locked {
+            return Err(Error::UnlockFailed);
+        }
+
+        self.locked = false;
+        Ok(MockFlashUnlocked { flash: self })
+    }
+
+    /// Checks whether the memory at `address` contains the given double-words
+    pub fn verify_dwords(&self, address: *mut usize, array: &[u64]) -> bool {
+        let start = address as usize;
+
+        array.iter().enumerate().all(|(i, dword)| {
+            let offset = start + i * 8;
+            self.memory
+                .get(offset..offset + 8)
+                .map_or(false, |bytes| bytes == dword.to_le_bytes())
+        })
+    }
+}
+
+impl<'a> MockFlashUnlocked<'a> {
+    pub fn status(&self) -> Result<(), Error> {
+        self.flash.status()
+    }
+
+    fn program(&mut self, start: usize, array: &[u64]) -> Result<(), Error> {
+        // PGAERR
+        if start % 8 != 0 {
+            return Err(Error::ProgrammingAlignment);
+        }
+
+        for (i, dword) in array.iter().enumerate() {
+            let offset = start + i * 8;
+            let target = self
+                .flash
+                .memory
+                .get_mut(offset..offset + 8)
+                .ok_or(Error::InvalidPage)?;
+
+            // PROGERR: the double-word was not erased
+            if target.iter().any(|byte| *byte != 0xFF) {
+                return Err(Error::ProgrammingSequence);
+            }
+
+            target.copy_from_slice(&dword.to_le_bytes());
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> FlashProgram for MockFlashUnlocked<'a> {
+    fn erase_page(&mut self, page: FlashPage) -> Result<(), Error> {
+        self.flash.error = None;
+
+        let size = self.flash.page_size_bytes() as usize;
+        let start = page.number() as usize * size;
+        self.flash.memory[start..start + size].fill(0xFF);
+
+        Ok(())
+    }
+
+    fn write_dwords(&mut self, address: *mut usize, array: &[u64]) -> Result<(), Error> {
+        self.flash.error = None;
+
+        let result = self.program(address as usize, array);
+        if let Err(error) = result {
+            self.flash.error = Some(error);
+        }
+
+        result
+    }
+
+    /// The mock reports not erased memory as [Error::ProgrammingSequence] anyway
+    fn write_dwords_no_erase_check(
+        &mut self,
+        address: *mut usize,
+        array: &[u64],
+    ) -> Result<(), Error> {
+        self.write_dwords(address, array)
+    }
+
+    /// There is no DMA to simulate, so after the channel check this is a plain write
+    fn write_dwords_dma(
+        &mut self,
+        _dma: &mut pac::DMA1,
+        channel: u8,
+        address: *mut usize,
+        array: &[u64],
+    ) -> Result<(), Error> {
+        if !(1..=7).contains(&channel) {
+            return Err(Error::InvalidChannel);
+        }
+
+        self.write_dwords(address, array)
+    }
+
+    /// Like the hardware, the lone word is rejected and nothing is written
+    fn write_word_illegal(&mut self, address: *mut u32, _value: u32) -> Result<(), Error> {
+        let start = address as usize;
+        if start + 4 > self.flash.memory.len() {
+            return Err(Error::InvalidPage);
+        }
+
+        // SIZERR
+        self.flash.error = Some(Error::Illegal);
+        Err(Error::Illegal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_after_erase_succeeds() {
+        let mut flash = MockFlash::new();
+        let page = flash.make_page(0x800).unwrap();
+        let mut unlocked = flash.unlock().unwrap();
+
+        unlocked.erase_page(page).unwrap();
+        unlocked
+            .write_dwords(0x800 as *mut usize, &[0xDEAD_BEEF, 0])
+            .unwrap();
+        drop(unlocked);
+
+        assert!(flash.locked);
+        assert!(flash.verify_dwords(0x800 as *mut usize, &[0xDEAD_BEEF, 0]));
+    }
+
+    #[test]
+    fn write_without_erase_fails() {
+        let mut flash = MockFlash::new();
+        let mut unlocked = flash.unlock().unwrap();
+
+        unlocked.write_dwords(0x800 as *mut usize, &[0]).unwrap();
+        assert_eq!(
+            unlocked.write_dwords(0x800 as *mut usize, &[0]),
+            Err(Error::ProgrammingSequence)
+        );
+        assert_eq!(unlocked.status(), Err(Error::ProgrammingSequence));
+    }
+
+    #[test]
+    fn unaligned_write_fails() {
+        let mut flash = MockFlash::new();
+        let mut unlocked = flash.unlock().unwrap();
+
+        assert_eq!(
+            unlocked.write_dwords(0x804 as *mut usize, &[0]),
+            Err(Error::ProgrammingAlignment)
+        );
+    }
+
+    #[test]
+    fn unlocking_twice_fails() {
+        let mut flash = MockFlash::new();
+        // Without the relock in drop, the flash stays unlocked
+        core::mem::forget(flash.unlock().unwrap());
+
+        assert!(matches!(flash.unlock(), Err(Error::UnlockFailed)));
+    }
+}
@@ -0,0 +1,129 @@
+//! Iteration over the flash pages, so that page addresses don't have to be computed by hand.
+
+/// A flash page and where it lies. Addresses are offsets from the start of the flash in the
+/// physical layout (bank 1 first), like the target addresses in `main`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlashSector {
+    /// Page number, counting on from bank 1 into bank 2 like [super::FlashPage]
+    pub number: u32,
+    /// 1 or 2
+    pub bank: u8,
+    /// First byte of the page
+    pub start_address: u32,
+    /// One past the last byte of the page
+    pub end_address: u32,
+}
+
+impl FlashSector {
+    /// Whether the page contains the given address
+    pub fn contains(&self, address: u32) -> bool {
+        (self.start_address..self.end_address).contains(&address)
+    }
+}
+
+/// Iterator over all pages of the flash, see [super::Flash::sector_map]
+#[derive(Debug, Clone)]
+pub struct SectorMap {
+    page_size: u32,
+    page_count: u32,
+    pages_per_bank: u32,
+    next: u32,
+}
+
+impl SectorMap {
+    /// Creates the map for a flash with `page_count` pages of `page_size` bytes. In dual-bank
+    /// mode, the second half of the pages belongs to bank 2.
+    pub fn new(page_size: u32, page_count: u32, dual_bank: bool) -> Self {
+        let pages_per_bank = if dual_bank {
+            page_count / 2
+        } else {
+            page_count
+        };
+
+        SectorMap {
+            page_size,
+            page_count,
+            pages_per_bank,
+            next: 0,
+        }
+    }
+}
+
+impl Iterator for SectorMap {
+    type Item = FlashSector;
+
+    fn next(&mut self) -> Option<FlashSector> {
+        if self.next >= self.page_count {
+            return None;
+        }
+
+        let number = self.next;
+        self.next += 1;
+
+        let start_address = number * self.page_size;
+        Some(FlashSector {
+            number,
+            bank: if number < self.pages_per_bank { 1 } else { 2 },
+            start_address,
+            end_address: start_address + self.page_size,
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = (self.page_count - self.next) as usize;
+        (remaining, Some(remaining))
+    }
+}
+
+impl ExactSizeIterator for SectorMap {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_bank_layout() {
+        let sectors: Vec<_> = SectorMap::new(0x800, 256, false).collect();
+
+        assert_eq!(sectors.len(), 256);
+        assert!(sectors.iter().all(|s| s.bank == 1));
+        assert_eq!(
+            sectors[255],
+            FlashSector {
+                number: 255,
+                bank: 1,
+                start_address: 0x7_F800,
+                end_address: 0x8_0000,
+            }
+        );
+    }
+
+    #[test]
+    fn dual_bank_layout() {
+        let sectors: Vec<_> = SectorMap::new(0x1000, 256, true).collect();
+
+        assert_eq!(sectors.iter().filter(|s| s.bank == 1).count(), 128);
+        assert_eq!(sectors[127].bank, 1);
+        assert_eq!(sectors[128].bank, 2);
+        assert_eq!(sectors[128].start_address, 0x8_0000);
+    }
+
+    #[test]
+    fn pages_are_contiguous() {
+        let sectors: Vec<_> = SectorMap::new(0x800, 16, false).collect();
+
+        for pair in sectors.windows(2) {
+            assert_eq!(pair[0].end_address, pair[1].start_address);
+        }
+    }
+
+    #[test]
+    fn finds_page_of_address() {
+        let sector = SectorMap::new(0x800, 256, false)
+            .find(|s| s.contains(0x1_0000))
+            .unwrap();
+
+        assert_eq!(sector.number, 32);
+        assert!(!sector.contains(sector.end_address));
+    }
+}
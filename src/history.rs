@@ -0,0 +1,174 @@
+use crate::flash::CorruptionResult;
+
+/// Number of attempts that are remembered
+const HISTORY_LEN: usize = 8;
+
+/// Marks the history as initialized. SRAM2 is not initialized by the runtime, so after a power
+/// cycle it contains garbage.
+const HISTORY_MAGIC: u32 = 0x4849_5355;
+
+/// One corruption attempt
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct HistoryEntry {
+    /// The timing value used for the attempt
+    pub middle: u32,
+    /// The address of the ECC error the probe after the attempt found, otherwise 0
+    pub ecc_address: u32,
+}
+
+/// Ring buffer of the last attempts.
+/// This lives in SRAM2, which (unlike the backup registers) is large enough to keep more than the
+/// current search state, and keeps its content across system resets as long as power stays on.
+#[repr(C)]
+pub struct CorruptionHistory {
+    magic: u32,
+    /// Total number of entries ever pushed
+    sequence: u32,
+    /// Timing value of the attempt that is currently running, pushed once the probe after the
+    /// next reset found out how it went. [None] once it was pushed
+    pending_middle: Option<u32>,
+    entries: [HistoryEntry; HISTORY_LEN],
+    /// The corruption that ended the last session, if any
+    result: Option<CorruptionResult>,
+}
+
+#[link_section = ".sram2"]
+static mut HISTORY: CorruptionHistory = CorruptionHistory {
+    magic: 0,
+    sequence: 0,
+    pending_middle: None,
+    entries: [HistoryEntry {
+        middle: 0,
+        ecc_address: 0,
+    }; HISTORY_LEN],
+    result: None,
+};
+
+impl CorruptionHistory {
+    /// Remembers the timing value of the attempt that is about to start
+    pub fn set_pending(&mut self, middle: u32) {
+        self.pending_middle = Some(middle);
+    }
+
+    /// Adds the outcome of the last attempt, overwriting the oldest entry if the buffer is full.
+    /// Does nothing if no attempt is pending
+    pub fn push_pending(&mut self, ecc_address: u32) {
+        let middle = match self.pending_middle.take() {
+            Some(middle) => middle,
+            None => return,
+        };
+
+        self.entries[self.sequence as usize % HISTORY_LEN] = HistoryEntry {
+            middle,
+            ecc_address,
+        };
+        self.sequence += 1;
+    }
+
+    /// The timing value of the attempt in flight, or of the newest entry if none is pending
+    pub fn last_middle(&self) -> u32 {
+        match self.pending_middle {
+            Some(middle) => middle,
+            None => self.iter().last().map_or(0, |(_, entry)| entry.middle),
+        }
+    }
+
+    /// Remembers a successful corruption
+    pub fn set_result(&mut self, result: CorruptionResult) {
+        self.result = Some(result);
+    }
+
+    /// The corruption that ended the last session, if any
+    pub fn result(&self) -> Option<CorruptionResult> {
+        self.result
+    }
+
+    /// Iterates over the stored entries from oldest to newest, together with their sequence number
+    pub fn iter(&self) -> impl Iterator<Item = (u32, HistoryEntry)> + '_ {
+        let count = (self.sequence as usize).min(HISTORY_LEN) as u32;
+        let first = self.sequence - count;
+
+        (first..self.sequence).map(move |seq| (seq, self.entries[seq as usize % HISTORY_LEN]))
+    }
+}
+
+/// Gives access to the history in SRAM2, resetting it first if it does not contain valid data
+pub fn with_history<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut CorruptionHistory) -> R,
+{
+    cortex_m::interrupt::free(|_| {
+        // SAFETY: We are single-threaded and interrupts are disabled, so nobody else can hold a
+        // reference
+        let history = unsafe { &mut *core::ptr::addr_of_mut!(HISTORY) };
+
+        if history.magic != HISTORY_MAGIC {
+            history.magic = HISTORY_MAGIC;
+            history.sequence = 0;
+            history.pending_middle = None;
+            history.result = None;
+        }
+
+        f(history)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn empty() -> CorruptionHistory {
+        CorruptionHistory {
+            magic: HISTORY_MAGIC,
+            sequence: 0,
+            pending_middle: None,
+            entries: [HistoryEntry {
+                middle: 0,
+                ecc_address: 0,
+            }; HISTORY_LEN],
+            result: None,
+        }
+    }
+
+    #[test]
+    fn last_middle_prefers_the_pending_attempt() {
+        let mut history = empty();
+        history.set_pending(10);
+        history.push_pending(0);
+        history.set_pending(20);
+
+        assert_eq!(history.last_middle(), 20);
+    }
+
+    #[test]
+    fn last_middle_falls_back_to_the_newest_entry() {
+        let mut history = empty();
+        history.set_pending(10);
+        history.push_pending(0x800);
+
+        assert_eq!(history.last_middle(), 10);
+        assert_eq!(history.iter().last().unwrap().1.ecc_address, 0x800);
+    }
+
+    #[test]
+    fn push_without_pending_attempt_is_ignored() {
+        let mut history = empty();
+        history.push_pending(0x800);
+
+        assert_eq!(history.iter().count(), 0);
+    }
+
+    #[test]
+    fn oldest_entries_are_overwritten() {
+        let mut history = empty();
+        for middle in 0..HISTORY_LEN as u32 + 2 {
+            history.set_pending(middle);
+            history.push_pending(0);
+        }
+
+        let (seq, entry) = history.iter().next().unwrap();
+        assert_eq!((seq, entry.middle), (2, 2));
+        assert_eq!(history.iter().count(), HISTORY_LEN);
+    }
+}
@@ -1,25 +1,939 @@
-use stm32l4::stm32l4x1::{self};
-
-pub fn set_green_led(state: bool) {
-    // PC7
-    let peripherals = unsafe { stm32l4x1::Peripherals::steal() };
-    peripherals.RCC.ahb2enr.modify(|_, w| w.gpiocen().set_bit());
-    peripherals.GPIOC.moder.modify(|_, w| w.moder7().output());
-    peripherals.GPIOC.odr.modify(|_, w| w.odr7().bit(state));
-}
-
-pub fn set_red_led(state: bool) {
-    // PB14
-    let peripherals = unsafe { stm32l4x1::Peripherals::steal() };
-    peripherals.RCC.ahb2enr.modify(|_, w| w.gpioben().set_bit());
-    peripherals.GPIOB.moder.modify(|_, w| w.moder14().output());
-    peripherals.GPIOB.odr.modify(|_, w| w.odr14().bit(state));
-}
-
-pub fn set_blue_led(state: bool) {
-    // PB1
-    let peripherals = unsafe { stm32l4x1::Peripherals::steal() };
-    peripherals.RCC.ahb2enr.modify(|_, w| w.gpioben().set_bit());
-    peripherals.GPIOB.moder.modify(|_, w| w.moder1().output());
-    peripherals.GPIOB.odr.modify(|_, w| w.odr1().bit(state));
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+
+use cortex_m::asm::{dmb, dsb, isb};
+use cortex_m::peripheral::syst::SystClkSource;
+use cortex_m::peripheral::DWT;
+#[cfg(feature = "itm-log")]
+use cortex_m::peripheral::TPIU;
+use stm32l4xx_hal::watchdog::IndependentWatchdog;
+
+use crate::boards::{BlueLed, GreenLed, RedLed, UserButton};
+use crate::pac;
+
+/// The core clock after reset (MSI at 4MHz). This is what the CPU and SysTick run at unless
+/// [init_clock_hsi16] is called
+pub const SYSCLK_HZ: u32 = 4_000_000;
+
+/// The clock frequency HSI16 runs at
+pub const HSI16_HZ: u32 = 16_000_000;
+
+static CURRENT_SYSCLK_HZ: AtomicU32 = AtomicU32::new(SYSCLK_HZ);
+
+/// The core clock the CPU currently runs at
+pub fn sysclk_hz() -> u32 {
+    CURRENT_SYSCLK_HZ.load(Ordering::SeqCst)
+}
+
+/// Switches the system clock from MSI to HSI16 and returns the new frequency.
+/// A faster clock means that each cycle of waiting before the write is shorter, so the timing of
+/// the corruption can be set more precisely (at the cost of 4x more values to search through).
+///
+/// The IWDG runs on LSI, not on the core clock, so its timeout stays the same in microseconds.
+/// It just corresponds to 4x more CPU cycles, which [iwdg_timeout_cycles] takes into account when
+/// passed the value returned here.
+pub fn init_clock_hsi16(rcc: &mut pac::RCC) -> u32 {
+    // SAFETY: Only the wait states in FLASH_ACR are changed, the RCC is passed in
+    let peripherals = unsafe { pac::Peripherals::steal() };
+
+    rcc.cr.modify(|_, w| w.hsion().set_bit());
+    while rcc.cr.read().hsirdy().bit_is_clear() {}
+
+    // The wait states have to be increased before the clock goes up. Two are enough for 16MHz in
+    // both voltage ranges (Range 1 would also work with zero)
+    // SAFETY: 0b010 is a valid LATENCY, two wait states
+    peripherals
+        .FLASH
+        .acr
+        .modify(|_, w| unsafe { w.latency().bits(0b010) });
+    while peripherals.FLASH.acr.read().latency().bits() != 0b010 {}
+
+    // SW = 0b01 selects HSI16, SWS reports when the switch is done
+    // SAFETY: HSI16 is ready and the wait states were increased before
+    rcc.cfgr.modify(|_, w| unsafe { w.sw().bits(0b01) });
+    while rcc.cfgr.read().sws().bits() != 0b01 {}
+
+    CURRENT_SYSCLK_HZ.store(HSI16_HZ, Ordering::SeqCst);
+    HSI16_HZ
+}
+
+/// Which of the flash caches in `FLASH_ACR` are used, see [apply_cache_config]
+#[derive(Debug, Clone, Copy)]
+pub struct CacheConfig {
+    pub icache: bool,
+    pub dcache: bool,
+}
+
+/// Enables or disables the instruction and data cache. With the caches on, reading back the
+/// target right after an attempt may be served from the cache instead of the flash cells.
+pub fn apply_cache_config(config: CacheConfig) {
+    if config.icache {
+        enable_icache();
+    } else {
+        disable_icache();
+    }
+
+    if config.dcache {
+        enable_dcache();
+    } else {
+        disable_dcache();
+    }
+}
+
+/// Sets `ICEN` in `FLASH_ACR`
+pub fn enable_icache() {
+    // SAFETY: Only ICEN in FLASH_ACR is changed
+    let peripherals = unsafe { pac::Peripherals::steal() };
+    peripherals.FLASH.acr.modify(|_, w| w.icen().set_bit());
+}
+
+/// Clears `ICEN` in `FLASH_ACR` and resets the instruction cache, so that it starts out empty
+/// when it is enabled again. The cache can only be reset while it is disabled
+pub fn disable_icache() {
+    // SAFETY: Only the instruction cache bits in FLASH_ACR are changed
+    let peripherals = unsafe { pac::Peripherals::steal() };
+    peripherals.FLASH.acr.modify(|_, w| w.icen().clear_bit());
+    cache_barrier();
+
+    peripherals.FLASH.acr.modify(|_, w| w.icrst().set_bit());
+    peripherals.FLASH.acr.modify(|_, w| w.icrst().clear_bit());
+}
+
+/// Sets `DCEN` in `FLASH_ACR`
+pub fn enable_dcache() {
+    // SAFETY: Only DCEN in FLASH_ACR is changed
+    let peripherals = unsafe { pac::Peripherals::steal() };
+    peripherals.FLASH.acr.modify(|_, w| w.dcen().set_bit());
+}
+
+/// Clears `DCEN` in `FLASH_ACR` and resets the data cache, like [disable_icache]
+pub fn disable_dcache() {
+    // SAFETY: Only the data cache bits in FLASH_ACR are changed
+    let peripherals = unsafe { pac::Peripherals::steal() };
+    peripherals.FLASH.acr.modify(|_, w| w.dcen().clear_bit());
+    cache_barrier();
+
+    peripherals.FLASH.acr.modify(|_, w| w.dcrst().set_bit());
+    peripherals.FLASH.acr.modify(|_, w| w.dcrst().clear_bit());
+}
+
+/// Makes sure that no access started before a cache was disabled still goes through it, and
+/// that the following instructions are fetched anew
+fn cache_barrier() {
+    dmb();
+    dsb();
+    isb();
+}
+
+/// Something that can switch an LED on and off. The pins depend on the board, see `boards`
+pub trait LedDriver {
+    fn set(&mut self, on: bool);
+}
+
+/// Something that reads whether a button is pressed. The pin depends on the board, see `boards`
+pub trait ButtonDriver {
+    fn is_pressed(&self) -> bool;
+}
+
+/// The LEDs of the board
+pub struct Leds {
+    pub green: GreenLed,
+    pub red: RedLed,
+    pub blue: BlueLed,
+}
+
+impl Leds {
+    pub fn set_all(&mut self, green: bool, red: bool, blue: bool) {
+        self.green.set(green);
+        self.red.set(red);
+        self.blue.set(blue);
+    }
+}
+
+/// Configures the LED pins and switches all LEDs off.
+/// This only touches the GPIO registers, so it is fine to call it again from fault handlers
+pub fn init_leds() -> Leds {
+    let mut leds = Leds {
+        green: GreenLed::new(),
+        red: RedLed::new(),
+        blue: BlueLed::new(),
+    };
+    leds.set_all(false, false, false);
+    leds
+}
+
+/// Busy-waits for the given number of milliseconds using SysTick, without interrupts.
+/// The watchdogs are fed while waiting, so this can also be used after one was started.
+pub fn delay_ms(ms: u32) {
+    // SAFETY: Only SysTick is used, and its interrupt is disabled while waiting
+    let mut core_peripherals = unsafe { cortex_m::Peripherals::steal() };
+    let syst = &mut core_peripherals.SYST;
+
+    syst.disable_interrupt();
+    syst.set_clock_source(SystClkSource::Core);
+    syst.set_reload(sysclk_hz() / 1_000 - 1);
+    syst.clear_current();
+    syst.enable_counter();
+
+    for _ in 0..ms {
+        // The shortest watchdog timeouts are much shorter than a millisecond, so feed them all
+        // the time
+        while !syst.has_wrapped() {
+            feed_watchdog();
+        }
+    }
+
+    syst.disable_counter();
+}
+
+/// CPU cycles per microsecond, see [configure_systick_us]
+static CYCLES_PER_US: AtomicU32 = AtomicU32::new(SYSCLK_HZ / 1_000_000);
+
+/// Calibrates [delay_us] for the given core clock. This has to be called again whenever the clock
+/// changes, e.g. after [init_clock_hsi16]
+pub fn configure_systick_us(sysclk_hz: u32) {
+    CYCLES_PER_US.store(sysclk_hz / 1_000_000, Ordering::SeqCst);
+}
+
+/// Busy-waits for at least the given number of microseconds using SysTick, without interrupts.
+/// Like [delay_ms], this takes over SysTick, so it must not be used while a deadline from
+/// [arm_systick_deadline] is pending.
+pub fn delay_us(us: u32) {
+    // SAFETY: Only SysTick is used, see delay_ms
+    let mut core_peripherals = unsafe { cortex_m::Peripherals::steal() };
+    let syst = &mut core_peripherals.SYST;
+
+    syst.disable_interrupt();
+    syst.set_clock_source(SystClkSource::Core);
+
+    // SysTick only has a 24 bit counter, so long delays are done in several rounds. A reload
+    // value of 0 would stop the counter, so each round is at least 2 cycles
+    let mut remaining = us.saturating_mul(CYCLES_PER_US.load(Ordering::SeqCst));
+    while remaining > 0 {
+        let round = remaining.min(0x00FF_FFFF).max(2);
+        syst.set_reload(round - 1);
+        syst.clear_current();
+        syst.enable_counter();
+        while !syst.has_wrapped() {}
+
+        remaining = remaining.saturating_sub(round);
+    }
+
+    syst.disable_counter();
+}
+
+/// Feeds whichever watchdog is running. Once started, neither of them can be stopped, so every
+/// loop that waits for something else than the watchdog reset has to call this
+pub fn feed_watchdog() {
+    feed_iwdg();
+
+    let counter = WWDG_COUNTER.load(Ordering::SeqCst);
+    if counter != 0 {
+        feed_wwdg(counter, WWDG_WINDOW.load(Ordering::SeqCst));
+    }
+}
+
+/// Reloads the IWDG counter. Writing the reload key does not start the watchdog if it is not
+/// running, so this can be called at any time
+fn feed_iwdg() {
+    // SAFETY: Only IWDG_KR is written, with the reload key (0xAAAA), which has no other effect
+    let peripherals = unsafe { pac::Peripherals::steal() };
+    peripherals
+        .IWDG
+        .kr
+        .write(|w| unsafe { w.key().bits(0xAAAA) });
+}
+
+/// Blinks the given LED `count` times, with `period_ms` on and `period_ms` off, followed by a
+/// pause of three periods so that repeated codes can be told apart.
+pub fn blink_code(led: &mut dyn LedDriver, count: u8, period_ms: u32) {
+    for _ in 0..count {
+        led.set(true);
+        delay_ms(period_ms);
+        led.set(false);
+        delay_ms(period_ms);
+    }
+
+    delay_ms(3 * period_ms);
+}
+
+/// Waits up to `timeout_ms` for the user button of the board to be pressed
+pub fn wait_for_button_press(timeout_ms: u32) -> bool {
+    let button = UserButton::new();
+
+    // The weak pull-up needs a moment to charge the pin, otherwise the first read can look like a
+    // press
+    delay_us(10);
+
+    for _ in 0..timeout_ms {
+        if button.is_pressed() {
+            return true;
+        }
+
+        delay_cycles(sysclk_hz() / 1_000);
+    }
+
+    false
+}
+
+/// The source of the last reset, as reported by `RCC_CSR`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetCause {
+    /// The independent watchdog (IWDG) fired, which is how every corruption attempt ends
+    IndependentWatchdog,
+    /// The window watchdog (WWDG) fired. This has its own flag (`WWDGRSTF`) in `RCC_CSR`, so
+    /// when the WWDG times the attempts, they end with this instead of `IndependentWatchdog`
+    WindowWatchdog,
+    /// Brown-out reset, this is also what a power-on reset looks like
+    BrownOut,
+    /// Software reset, e.g. from a debugger or `SCB::sys_reset`
+    Software,
+    /// The NRST pin was pulled low, e.g. by the reset button
+    Pin,
+    /// None of the flags above were set
+    Other,
+}
+
+pub fn read_reset_cause() -> ResetCause {
+    // SAFETY: RCC_CSR is only read
+    let peripherals = unsafe { pac::Peripherals::steal() };
+    let csr = peripherals.RCC.csr.read();
+
+    // Internal resets also pull NRST low, so PINRSTF is set for all of them and must be checked
+    // last
+    if csr.iwdgrstf().bit_is_set() {
+        ResetCause::IndependentWatchdog
+    } else if csr.wwdgrstf().bit_is_set() {
+        ResetCause::WindowWatchdog
+    } else if csr.borrstf().bit_is_set() {
+        ResetCause::BrownOut
+    } else if csr.sftrstf().bit_is_set() {
+        ResetCause::Software
+    } else if csr.pinrstf().bit_is_set() {
+        ResetCause::Pin
+    } else {
+        ResetCause::Other
+    }
+}
+
+pub fn clear_reset_cause() {
+    // The reset flags are sticky until RMVF is written
+    // SAFETY: RMVF only clears the reset flags, the other bits of RCC_CSR are written back
+    let peripherals = unsafe { pac::Peripherals::steal() };
+    peripherals.RCC.csr.modify(|_, w| w.rmvf().set_bit());
+}
+
+/// Computes a CRC32 (Ethernet polynomial 0x04C11DB7, initial value 0xFFFFFFFF) over the given
+/// backup register values using the CRC peripheral
+pub fn compute_backup_crc(regs: &[u32]) -> u32 {
+    // SAFETY: Nothing else uses the CRC peripheral, and only its clock enable is changed in RCC
+    let peripherals = unsafe { pac::Peripherals::steal() };
+    peripherals.RCC.ahb1enr.modify(|_, w| w.crcen().set_bit());
+
+    // The reset values of the CRC peripheral already select the polynomial and initial value,
+    // so we only need to restart the calculation
+    peripherals.CRC.cr.modify(|_, w| w.reset().set_bit());
+    for reg in regs {
+        // SAFETY: CRC_DR takes any 32 bit value as input
+        peripherals.CRC.dr.write(|w| unsafe { w.bits(*reg) });
+    }
+
+    peripherals.CRC.dr.read().bits()
+}
+
+/// Busy-waits for (at least) the given number of CPU cycles, using the DWT cycle counter.
+/// Unlike a counting loop, this does not depend on what the compiler makes of the loop body.
+pub fn delay_cycles(n: u32) {
+    // SAFETY: The cycle counter is only started, which doesn't disturb its other users
+    let mut core_peripherals = unsafe { cortex_m::Peripherals::steal() };
+    core_peripherals.DCB.enable_trace();
+    core_peripherals.DWT.enable_cycle_counter();
+
+    // The subtraction handles the counter wrapping around
+    let start = DWT::cycle_count();
+    while DWT::cycle_count().wrapping_sub(start) < n {}
+}
+
+/// Time since the DWT cycle counter was started, in microseconds. At 4MHz, this wraps around
+/// after about 18 minutes.
+pub fn uptime_us() -> u32 {
+    // SAFETY: Same as in delay_cycles
+    let mut core_peripherals = unsafe { cortex_m::Peripherals::steal() };
+    core_peripherals.DCB.enable_trace();
+    core_peripherals.DWT.enable_cycle_counter();
+
+    DWT::cycle_count() / (sysclk_hz() / 1_000_000)
+}
+
+/// Set by the SysTick handler once the deadline armed by [arm_systick_deadline] has passed
+static SYSTICK_DEADLINE_REACHED: AtomicBool = AtomicBool::new(false);
+
+/// Starts SysTick as a one-shot timer that fires after the given number of CPU cycles.
+/// SysTick only has a 24 bit counter, so larger values are clamped.
+pub fn arm_systick_deadline(cycles: u32) {
+    // SAFETY: Only SysTick is used, which belongs to the deadline until on_systick stops it
+    let mut core_peripherals = unsafe { cortex_m::Peripherals::steal() };
+    let syst = &mut core_peripherals.SYST;
+
+    SYSTICK_DEADLINE_REACHED.store(false, Ordering::SeqCst);
+
+    // The interrupt fires when the counter wraps from 0 to the reload value, so after reload + 1
+    // cycles
+    syst.disable_counter();
+    syst.set_clock_source(SystClkSource::Core);
+    syst.set_reload(cycles.clamp(1, 0x00FF_FFFF) - 1);
+    syst.clear_current();
+    syst.enable_interrupt();
+    syst.enable_counter();
+}
+
+/// Must be called from the SysTick exception handler
+pub fn on_systick() {
+    // This is a one-shot deadline, so stop the timer from firing again
+    // SAFETY: Only stops SysTick, which arm_systick_deadline handed to this handler
+    let mut core_peripherals = unsafe { cortex_m::Peripherals::steal() };
+    core_peripherals.SYST.disable_interrupt();
+    core_peripherals.SYST.disable_counter();
+
+    SYSTICK_DEADLINE_REACHED.store(true, Ordering::SeqCst);
+}
+
+/// Whether the deadline armed by [arm_systick_deadline] has passed
+pub fn systick_deadline_reached() -> bool {
+    SYSTICK_DEADLINE_REACHED.load(Ordering::SeqCst)
+}
+
+/// Frequency of the LSI oscillator the IWDG runs on. The actual frequency can vary by a few
+/// percent, see the datasheet.
+pub const LSI_HZ: u32 = 32_000;
+
+/// IWDG prescaler divisors, with the value of the PR register as discriminant
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub enum IwdgPrescaler {
+    Div4 = 0b000,
+    Div8 = 0b001,
+    Div16 = 0b010,
+    Div32 = 0b011,
+    Div64 = 0b100,
+    Div128 = 0b101,
+    Div256 = 0b110,
+}
+
+impl IwdgPrescaler {
+    pub fn divisor(&self) -> u32 {
+        4 << (*self as u32)
+    }
+}
+
+/// Returns the IWDG timeout for the given configuration in CPU cycles at `sysclk_hz`:
+/// `t = (reload + 1) / (LSI_HZ / prescaler_divisor)`, i.e. from ~125µs (/4, reload 0) to ~32s
+/// (/256, reload 0xFFF).
+pub fn iwdg_timeout_cycles(prescaler: IwdgPrescaler, reload: u16, sysclk_hz: u32) -> u32 {
+    let lsi_ticks = (reload as u64 + 1) * prescaler.divisor() as u64;
+
+    (lsi_ticks * sysclk_hz as u64 / LSI_HZ as u64) as u32
+}
+
+/// Stops the IWDG while the CPU is halted by a debugger (`DBG_IWDG_STOP` in `DBGMCU_APB1FZR1`),
+/// so that sitting at a breakpoint doesn't end the attempt with a reset. Without a debugger, the
+/// CPU is never halted, so this has no effect in normal operation.
+pub fn freeze_iwdg_in_debug() {
+    // SAFETY: Only DBG_IWDG_STOP is changed, which has no effect without a debugger
+    let peripherals = unsafe { pac::Peripherals::steal() };
+    peripherals
+        .DBGMCU
+        .apb1fzr1
+        .modify(|_, w| w.dbg_iwdg_stop().set_bit());
+}
+
+/// Stops the RTC while the CPU is halted by a debugger (`DBG_RTC_STOP` in `DBGMCU_APB1FZR1`), so
+/// that the Stop 2 wakeup timer doesn't run on. Like [freeze_iwdg_in_debug], this only matters
+/// with a debugger attached.
+pub fn freeze_rtc_in_debug() {
+    // SAFETY: Only DBG_RTC_STOP is changed, like in freeze_iwdg_in_debug
+    let peripherals = unsafe { pac::Peripherals::steal() };
+    peripherals
+        .DBGMCU
+        .apb1fzr1
+        .modify(|_, w| w.dbg_rtc_stop().set_bit());
+}
+
+/// Starts the IWDG with the raw prescaler and reload values, which allows shorter timeouts than
+/// the millisecond-based HAL interface. The returned watchdog can be used to feed it.
+pub fn configure_iwdg_raw(prescaler: IwdgPrescaler, reload: u16) -> IndependentWatchdog {
+    // RLR is only 12 bits wide
+    debug_assert!(reload <= 0xFFF, "IWDG reload value out of range");
+
+    // SAFETY: Only the IWDG is used. Once started, it can only be fed, which the returned HAL
+    // watchdog does
+    let peripherals = unsafe { pac::Peripherals::steal() };
+    let iwdg = &peripherals.IWDG;
+
+    // Configuration sequence from the reference manual: start the watchdog, enable register
+    // access, configure and wait until the new values have been taken over
+    // SAFETY: The keys are the ones from the reference manual, the prescaler is a valid PR value
+    // and the reload value fits into RLR
+    iwdg.kr.write(|w| unsafe { w.key().bits(0xCCCC) });
+    iwdg.kr.write(|w| unsafe { w.key().bits(0x5555) });
+    iwdg.pr.write(|w| unsafe { w.pr().bits(prescaler as u8) });
+    iwdg.rlr.write(|w| unsafe { w.rl().bits(reload) });
+    while iwdg.sr.read().bits() != 0 {}
+    iwdg.kr.write(|w| unsafe { w.key().bits(0xAAAA) });
+
+    // SAFETY: The HAL type only wraps the IWDG configured above
+    let dp = unsafe { stm32l4xx_hal::stm32::Peripherals::steal() };
+    IndependentWatchdog::new(dp.IWDG)
+}
+
+/// WWDG prescaler (`WDGTB`), with the value of the field as discriminant
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub enum WwdgPrescaler {
+    Div1 = 0b00,
+    Div2 = 0b01,
+    Div4 = 0b10,
+    Div8 = 0b11,
+}
+
+impl WwdgPrescaler {
+    pub fn divisor(&self) -> u32 {
+        1 << (*self as u32)
+    }
+}
+
+/// Returns the WWDG timeout in CPU cycles when started with `counter`. The WWDG counts down at
+/// PCLK1 / 4096 / prescaler and resets once bit 6 of the counter clears, i.e. after
+/// `(counter & 0x3F) + 1` ticks. PCLK1 equals SYSCLK, as the APB prescaler is not changed.
+pub fn wwdg_timeout_cycles(prescaler: WwdgPrescaler, counter: u8) -> u32 {
+    4096 * prescaler.divisor() * ((counter & 0x3F) as u32 + 1)
+}
+
+/// The counter and window [arm_wwdg] started the WWDG with, for [feed_watchdog]. The counter is 0
+/// while the WWDG is not running
+static WWDG_COUNTER: AtomicU8 = AtomicU8::new(0);
+static WWDG_WINDOW: AtomicU8 = AtomicU8::new(0);
+
+/// Starts the WWDG. Unlike the IWDG, it runs on the core clock, so its timing doesn't drift
+/// against the CPU, and it can also reset when it is refreshed too early: refreshing while the
+/// counter is above `window` causes a reset. Pass 0x7F to allow refreshing at any time.
+///
+/// The WWDG is stopped in Stop modes, so it does not work with `stop2-between-attempts`.
+pub fn arm_wwdg(prescaler: WwdgPrescaler, window: u8, counter: u8) {
+    // Bit 6 must be set, otherwise the WWDG resets immediately
+    debug_assert!(
+        (0x40..=0x7F).contains(&counter),
+        "WWDG counter out of range"
+    );
+    debug_assert!(window <= 0x7F, "WWDG window out of range");
+
+    // SAFETY: Only the WWDG and its clock enable are changed
+    let peripherals = unsafe { pac::Peripherals::steal() };
+    peripherals.RCC.apb1enr1.modify(|_, w| w.wwdgen().set_bit());
+
+    let wwdg = &peripherals.WWDG;
+    // SAFETY: window and counter were checked to fit into 7 bits, the prescaler is a valid WDGTB
+    wwdg.cfr
+        .write(|w| unsafe { w.w().bits(window).wdgtb().bits(prescaler as u8) });
+    // Once enabled, the WWDG can only be stopped by a reset
+    wwdg.cr
+        .write(|w| unsafe { w.t().bits(counter).wdga().set_bit() });
+
+    WWDG_WINDOW.store(window, Ordering::SeqCst);
+    WWDG_COUNTER.store(counter, Ordering::SeqCst);
+}
+
+/// Reloads the WWDG counter with `counter`, unless it is still above `window`, where a refresh
+/// would reset the MCU. Called often enough, the counter drops into the window in between
+fn feed_wwdg(counter: u8, window: u8) {
+    // SAFETY: Only WWDG_CR is written, with the values arm_wwdg started the WWDG with
+    let peripherals = unsafe { pac::Peripherals::steal() };
+    let wwdg = &peripherals.WWDG;
+
+    if wwdg.cr.read().t().bits() > window {
+        return;
+    }
+    wwdg.cr
+        .write(|w| unsafe { w.t().bits(counter).wdga().set_bit() });
+}
+
+/// Configures USART2 on PA2 (TX) and PA3 (RX), which is connected to the ST-LINK virtual COM
+/// port on Nucleo boards. Only transmitting is used, by polling, so no interrupts are needed.
+#[cfg(feature = "uart-log")]
+pub fn init_usart2(baud: u32, rcc: &pac::RCC, gpioa: &pac::GPIOA) {
+    rcc.ahb2enr.modify(|_, w| w.gpioaen().set_bit());
+    rcc.apb1enr1.modify(|_, w| w.usart2en().set_bit());
+
+    // PA2 and PA3 use alternate function 7 for USART2
+    gpioa
+        .moder
+        .modify(|_, w| w.moder2().alternate().moder3().alternate());
+    gpioa.afrl.modify(|_, w| w.afrl2().af7().afrl3().af7());
+
+    // SAFETY: USART2 is only used for logging, by this function and Usart2Writer
+    let peripherals = unsafe { pac::Peripherals::steal() };
+    let usart = &peripherals.USART2;
+
+    // USART2 is clocked by PCLK1, which equals SYSCLK as the APB prescaler is not changed
+    usart.cr1.modify(|_, w| w.ue().clear_bit());
+    // SAFETY: BRR takes any divider, a wrong one only garbles the output
+    usart.brr.write(|w| unsafe { w.bits(sysclk_hz() / baud) });
+    usart.cr1.modify(|_, w| w.te().set_bit().ue().set_bit());
+}
+
+/// Waits until everything written to USART2 has actually been sent
+#[cfg(feature = "uart-log")]
+pub fn flush_usart2() {
+    // SAFETY: Only the status register of USART2 is read
+    let peripherals = unsafe { pac::Peripherals::steal() };
+    while peripherals.USART2.isr.read().tc().bit_is_clear() {}
+}
+
+/// Allows using `write!` with USART2, which must have been set up with [init_usart2]
+#[cfg(feature = "uart-log")]
+pub struct Usart2Writer;
+
+#[cfg(feature = "uart-log")]
+impl core::fmt::Write for Usart2Writer {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        // SAFETY: USART2 was set up by init_usart2 and is only written here, one byte at a time
+        // once TXE says that the previous one was taken
+        let peripherals = unsafe { pac::Peripherals::steal() };
+        let usart = &peripherals.USART2;
+
+        for byte in s.bytes() {
+            while usart.isr.read().txe().bit_is_clear() {}
+            usart.tdr.write(|w| unsafe { w.bits(byte as u32) });
+        }
+
+        Ok(())
+    }
+}
+
+/// Sets up tracing via the SWO pin (PB3) in UART (NRZ) mode at `swo_freq_hz`, with all ITM
+/// stimulus ports enabled. The probe must be configured for the same SWO frequency.
+#[cfg(feature = "itm-log")]
+pub fn init_itm(tpiu: &mut TPIU, dwt: &mut DWT, core_freq_hz: u32, swo_freq_hz: u32) {
+    // SAFETY: Besides the TPIU and DWT passed in, only the ITM, TRCENA and TRACE_IOEN are
+    // touched, which nothing else uses
+    let mut core_peripherals = unsafe { cortex_m::Peripherals::steal() };
+    let peripherals = unsafe { pac::Peripherals::steal() };
+
+    // TRACE_IOEN in DBGMCU_CR, with TRACE_MODE left at 0 (asynchronous, only SWO is used).
+    // PB3 is TRACESWO after reset, so it doesn't need to be configured
+    // SAFETY: Only bit 5 is set, the other bits are written back as read
+    peripherals
+        .DBGMCU
+        .cr
+        .modify(|r, w| unsafe { w.bits(r.bits() | 1 << 5) });
+
+    // TRCENA, without it neither the DWT nor the ITM work
+    core_peripherals.DCB.enable_trace();
+
+    // SAFETY: The values are the ones the ARMv7-M architecture manual gives for SWO output in NRZ
+    // mode, and LAR gets the documented unlock key
+    unsafe {
+        // SPPR = 2 selects NRZ encoding. The SWO clock is the core clock divided by ACPR + 1, so
+        // the core clock should be a multiple of the SWO frequency
+        tpiu.sppr.write(2);
+        tpiu.acpr.write(core_freq_hz / swo_freq_hz - 1);
+        // Disable the formatter, which is only needed for the parallel trace port
+        tpiu.ffcr.write(0x100);
+
+        // SYNCTAP = 1, synchronization packets every 2^24 cycles so the probe can find the start
+        // of packets
+        dwt.ctrl.modify(|r| r & !(0b11 << 10) | 0b01 << 10);
+        dwt.enable_cycle_counter();
+
+        let itm = &mut core_peripherals.ITM;
+        itm.lar.write(0xC5AC_CE55);
+        // TraceBusID 1, SYNCENA and ITMENA
+        itm.tcr.write(1 << 16 | 1 << 2 | 1);
+        itm.ter[0].write(u32::MAX);
+    }
+}
+
+/// Writes `data` to the given ITM stimulus port. Blocks while the ITM FIFO is full
+#[cfg(feature = "itm-log")]
+pub fn itm_write(port: u8, data: &[u8]) {
+    // SAFETY: Only the stimulus port is written, write_all waits until the FIFO has room
+    let mut core_peripherals = unsafe { cortex_m::Peripherals::steal() };
+    cortex_m::itm::write_all(&mut core_peripherals.ITM.stim[port as usize], data);
+}
+
+/// Allows using `write!` with ITM stimulus port 0, which must have been set up with [init_itm]
+#[cfg(feature = "itm-log")]
+pub struct ItmWriter;
+
+#[cfg(feature = "itm-log")]
+impl core::fmt::Write for ItmWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        itm_write(0, s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Prints to the debugger's console via semihosting. Each call stops the core until the debugger
+/// has handled it, and without a debugger attached, the breakpoint instruction hard-faults
+#[cfg(feature = "semihosting-log")]
+pub fn semihosting_write(s: &str) {
+    let _ = cortex_m_semihosting::hprint!("{}", s);
+}
+
+/// Allows using `write!` with [semihosting_write]
+#[cfg(feature = "semihosting-log")]
+pub struct SemihostingWriter;
+
+#[cfg(feature = "semihosting-log")]
+impl core::fmt::Write for SemihostingWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        semihosting_write(s);
+        Ok(())
+    }
+}
+
+/// Start of the main flash memory
+pub const FLASH_BASE: u32 = 0x0800_0000;
+
+/// End of this tool's image in the flash: the code and read-only data, followed by the initial
+/// values of `.data`, which the startup code copies to RAM from there
+pub fn image_end() -> u32 {
+    // Defined by the cortex-m-rt linker script
+    extern "C" {
+        static __sidata: u32;
+        static __sdata: u32;
+        static __edata: u32;
+    }
+
+    // SAFETY: Only the addresses of the linker symbols are taken, they are never read
+    let (sidata, sdata, edata) = unsafe {
+        (
+            core::ptr::addr_of!(__sidata) as u32,
+            core::ptr::addr_of!(__sdata) as u32,
+            core::ptr::addr_of!(__edata) as u32,
+        )
+    };
+
+    sidata + (edata - sdata)
+}
+
+/// Makes the flash from [FLASH_BASE] up to `protected_end` read-only using the MPU, so that a
+/// miscalculated address can't make the tool overwrite itself.
+///
+/// Any write to the region causes a MemManage fault, which is escalated to a `HardFault` since
+/// the MemManage handler is not enabled. The flash is also mapped at address 0 when booting from
+/// it (which is what the tool uses for writing), so the same range is protected there too.
+///
+/// The size of the region must be a power of two of at least 32 bytes.
+pub fn configure_mpu_flash_readonly(protected_end: u32) {
+    let size = protected_end - FLASH_BASE;
+    debug_assert!(
+        size.is_power_of_two() && size >= 32,
+        "MPU region size must be a power of two"
+    );
+
+    // RASR: SIZE is encoded as log2(size) - 1, AP = 0b110 is read-only for privileged and
+    // unprivileged code, C = 1 as flash is normal cacheable memory. XN stays cleared, as we
+    // execute from this region
+    const RASR_ENABLE: u32 = 1;
+    const RASR_C: u32 = 1 << 17;
+    const RASR_AP_READ_ONLY: u32 = 0b110 << 24;
+    let rasr = RASR_AP_READ_ONLY | RASR_C | ((size.trailing_zeros() - 1) << 1) | RASR_ENABLE;
+
+    // CTRL: PRIVDEFENA keeps the default memory map for everything not covered by a region
+    const CTRL_ENABLE: u32 = 1;
+    const CTRL_PRIVDEFENA: u32 = 1 << 2;
+
+    // SAFETY: The MPU is only configured here
+    let core_peripherals = unsafe { cortex_m::Peripherals::steal() };
+    let mpu = &core_peripherals.MPU;
+
+    // SAFETY: The regions only forbid writes to the flash below protected_end, where nothing is
+    // written on purpose. Reads and execution are allowed as before
+    unsafe {
+        // Region 0: the flash itself
+        mpu.rnr.write(0);
+        mpu.rbar.write(FLASH_BASE);
+        mpu.rasr.write(rasr);
+
+        // Region 1: the boot alias at address 0
+        mpu.rnr.write(1);
+        mpu.rbar.write(0);
+        mpu.rasr.write(rasr);
+
+        mpu.ctrl.write(CTRL_ENABLE | CTRL_PRIVDEFENA);
+    }
+
+    // Make sure the new configuration is used for all following accesses
+    dsb();
+    isb();
+}
+
+/// What ends Stop 2 mode in [enter_stop2]
+#[cfg(feature = "stop2-between-attempts")]
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub enum WakeupSource {
+    /// The RTC wakeup timer, after the given number of RTC/16 ticks (0.5ms each with LSI)
+    RtcWakeupTimer(u16),
+    /// A press of the user button on PC13
+    Button,
+}
+
+/// Enters Stop 2 mode until the given wakeup source fires.
+///
+/// The IWDG keeps running in Stop 2 (unless the IWDG_STOP option bit is cleared), so a started
+/// watchdog still resets the MCU while sleeping. Interrupts stay masked: the wakeup interrupt only
+/// ends WFI, its handler never runs.
+#[cfg(feature = "stop2-between-attempts")]
+pub fn enter_stop2(wakeup_source: WakeupSource) {
+    use cortex_m::peripheral::NVIC;
+    use pac::Interrupt;
+
+    // SAFETY: Only the wakeup source, PWR_CR1 and SLEEPDEEP are changed, and the interrupt
+    // handlers stay masked, so nothing else runs in between
+    let mut core_peripherals = unsafe { cortex_m::Peripherals::steal() };
+    let peripherals = unsafe { pac::Peripherals::steal() };
+
+    let interrupt = match wakeup_source {
+        WakeupSource::RtcWakeupTimer(ticks) => {
+            let rtc = &peripherals.RTC;
+
+            // Disable the write protection of the RTC registers
+            // SAFETY: The unlock keys from the reference manual
+            rtc.wpr.write(|w| unsafe { w.key().bits(0xCA) });
+            rtc.wpr.write(|w| unsafe { w.key().bits(0x53) });
+
+            // The wakeup timer can only be configured while it is disabled
+            rtc.cr.modify(|_, w| w.wute().clear_bit());
+            while rtc.isr.read().wutwf().bit_is_clear() {}
+            // SAFETY: WUT is 16 bits wide, like the ticks
+            rtc.wutr.write(|w| unsafe { w.wut().bits(ticks) });
+            rtc.isr.modify(|_, w| w.wutf().clear_bit());
+            // SAFETY: WUCKSEL = 0b000 selects RTC/16
+            rtc.cr.modify(|_, w| unsafe {
+                w.wucksel().bits(0b000).wutie().set_bit().wute().set_bit()
+            });
+
+            // SAFETY: Any value other than the keys enables the write protection again
+            rtc.wpr.write(|w| unsafe { w.key().bits(0xFF) });
+
+            // The RTC wakeup event is connected to EXTI line 20
+            peripherals.EXTI.rtsr1.modify(|_, w| w.tr20().set_bit());
+            peripherals.EXTI.imr1.modify(|_, w| w.mr20().set_bit());
+
+            Interrupt::RTC_WKUP
+        }
+        WakeupSource::Button => {
+            configure_button();
+
+            // Route PC13 to EXTI line 13, the button pulls the pin low when pressed
+            peripherals
+                .RCC
+                .apb2enr
+                .modify(|_, w| w.syscfgen().set_bit());
+            // SAFETY: 0b010 selects port C
+            peripherals
+                .SYSCFG
+                .exticr4
+                .modify(|_, w| unsafe { w.exti13().bits(0b010) });
+            peripherals.EXTI.ftsr1.modify(|_, w| w.tr13().set_bit());
+            peripherals.EXTI.imr1.modify(|_, w| w.mr13().set_bit());
+
+            Interrupt::EXTI15_10
+        }
+    };
+
+    // LPMS = 0b010 selects Stop 2
+    // SAFETY: 0b010 is a valid LPMS value
+    peripherals
+        .PWR
+        .cr1
+        .modify(|_, w| unsafe { w.lpms().bits(0b010) });
+    core_peripherals.SCB.set_sleepdeep();
+
+    cortex_m::interrupt::free(|_| {
+        // With PRIMASK set, a pending interrupt still ends WFI, but the handler is not called
+        // SAFETY: For the same reason, unmasking can't break the critical section
+        unsafe { NVIC::unmask(interrupt) };
+        cortex_m::asm::wfi();
+        NVIC::mask(interrupt);
+    });
+
+    core_peripherals.SCB.clear_sleepdeep();
+
+    // Clear the wakeup flags, otherwise the next WFI returns immediately
+    peripherals
+        .EXTI
+        .pr1
+        .write(|w| w.pr20().set_bit().pr13().set_bit());
+    peripherals.RTC.isr.modify(|_, w| w.wutf().clear_bit());
+    NVIC::unpend(interrupt);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn iwdg_shortest_timeout() {
+        // 125µs at 4MHz
+        assert_eq!(iwdg_timeout_cycles(IwdgPrescaler::Div4, 0, SYSCLK_HZ), 500);
+    }
+
+    #[test]
+    fn iwdg_longest_timeout() {
+        // 32.768s, at 4MHz and at 80MHz, which doesn't overflow
+        assert_eq!(
+            iwdg_timeout_cycles(IwdgPrescaler::Div256, 0xFFF, SYSCLK_HZ),
+            131_072_000
+        );
+        assert_eq!(
+            iwdg_timeout_cycles(IwdgPrescaler::Div256, 0xFFF, 80_000_000),
+            2_621_440_000
+        );
+    }
+
+    #[test]
+    fn iwdg_timeout_scales_with_the_clock() {
+        assert_eq!(iwdg_timeout_cycles(IwdgPrescaler::Div4, 0, HSI16_HZ), 2_000);
+        assert_eq!(
+            iwdg_timeout_cycles(IwdgPrescaler::Div32, 99, SYSCLK_HZ),
+            400_000
+        );
+    }
+
+    #[test]
+    fn iwdg_prescaler_divisors() {
+        let prescalers = [
+            IwdgPrescaler::Div4,
+            IwdgPrescaler::Div8,
+            IwdgPrescaler::Div16,
+            IwdgPrescaler::Div32,
+            IwdgPrescaler::Div64,
+            IwdgPrescaler::Div128,
+            IwdgPrescaler::Div256,
+        ];
+        let divisors: Vec<u32> = prescalers.iter().map(|p| p.divisor()).collect();
+
+        assert_eq!(divisors, [4, 8, 16, 32, 64, 128, 256]);
+    }
+
+    #[test]
+    fn wwdg_shortest_timeout() {
+        // Counter 0x40 resets on the next tick
+        assert_eq!(wwdg_timeout_cycles(WwdgPrescaler::Div1, 0x40), 4096);
+    }
+
+    #[test]
+    fn wwdg_longest_timeout() {
+        assert_eq!(wwdg_timeout_cycles(WwdgPrescaler::Div1, 0x7F), 262_144);
+        assert_eq!(wwdg_timeout_cycles(WwdgPrescaler::Div8, 0x7F), 2_097_152);
+    }
+
+    #[test]
+    fn wwdg_prescaler_divisors() {
+        assert_eq!(wwdg_timeout_cycles(WwdgPrescaler::Div2, 0x40), 2 * 4096);
+        assert_eq!(wwdg_timeout_cycles(WwdgPrescaler::Div4, 0x40), 4 * 4096);
+        assert_eq!(wwdg_timeout_cycles(WwdgPrescaler::Div8, 0x40), 8 * 4096);
+    }
 }
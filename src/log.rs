@@ -0,0 +1,90 @@
+//! Building blocks of the `flash_log!` lines, which look like
+//! `[RESET:042 T:1234µs STATE:1] INFO message addr=0x10020` so that session logs can be parsed.
+
+use core::fmt;
+
+/// Severity of a `flash_log!` line. `Debug` lines are only printed with the `verbose-log` feature
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl LogLevel {
+    /// Whether lines of this level are printed. Constant, so disabled lines are compiled out
+    pub const fn is_enabled(self) -> bool {
+        cfg!(feature = "verbose-log") || !matches!(self, LogLevel::Debug)
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            LogLevel::Debug => "DEBUG",
+            LogLevel::Info => "INFO",
+            LogLevel::Warn => "WARN",
+            LogLevel::Error => "ERROR",
+        })
+    }
+}
+
+/// The bracketed part at the start of each line
+pub struct LogPrefix {
+    /// Reset counter from backup register 4
+    pub resets: u32,
+    /// Microseconds since the cycle counter was started
+    pub micros: u32,
+    /// Search state from backup register 3
+    pub state: u32,
+}
+
+impl fmt::Display for LogPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[RESET:{:03} T:{}µs STATE:{}]",
+            self.resets, self.micros, self.state
+        )
+    }
+}
+
+/// `name=value` pairs, with the values in hex as most of them are addresses
+pub struct LogFields<'a>(pub &'a [(&'a str, u32)]);
+
+impl fmt::Display for LogFields<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, value) in self.0 {
+            write!(f, " {}={:#x}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_is_formatted() {
+        let prefix = LogPrefix {
+            resets: 42,
+            micros: 1234,
+            state: 1,
+        };
+        let line = format!(
+            "{} {} {}{}",
+            prefix,
+            LogLevel::Info,
+            "hit",
+            LogFields(&[("addr", 0x10020), ("middle", 0x40)])
+        );
+
+        assert_eq!(
+            line,
+            "[RESET:042 T:1234µs STATE:1] INFO hit addr=0x10020 middle=0x40"
+        );
+    }
+}
@@ -1,31 +1,155 @@
-#![no_std]
-#![no_main]
+// On the host, `cargo test` needs std and the test harness' main function
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+// Most of the firmware is not used by the host tests
+#![cfg_attr(test, allow(dead_code, unused_imports))]
+#![deny(unsafe_op_in_unsafe_fn)]
 
 use cortex_m_rt::{entry, exception};
 use cortex_m::interrupt::{self, Mutex};
 use core::cell::RefCell;
-use stm32l4::stm32l4x1;
-use rtt_target::{rprintln, rtt_init_print};
+#[cfg(not(any(feature = "uart-log", feature = "itm-log", feature = "semihosting-log")))]
+use rtt_target::{rprintln, rtt_init, set_print_channel, DownChannel};
 use stm32l4xx_hal::prelude::*;
-use stm32l4xx_hal::watchdog::{IndependentWatchdog};
-use stm32l4xx_hal::time::MilliSeconds;
 use stm32l4xx_hal::rtc::{Rtc, RtcClockSource, RtcConfig};
 
-// Which address should be corrupted, with an allowed range
-const APPROXIMATE_ADDRESS_TO_CORRUPT: usize = 0x1_0000;
+// Which addresses should be corrupted, with an allowed range. They are targeted one after
+// another: once an address has been hit, the search starts over for the next one.
+// The addresses are offsets in the physical flash layout (bank 1 first), which only makes a
+// difference when the banks are swapped with BFB2
+const TARGET_ADDRESSES: &[usize] = &[0x1_0000];
 const CORRUPT_RANGE: usize = 0x20;
+static_assertions::const_assert!(!TARGET_ADDRESSES.is_empty());
 static_assertions::const_assert!(CORRUPT_RANGE > 0);
+static_assertions::const_assert!(targets_on_distinct_pages());
+
+// Number of double-words covering the corrupt range
+const CORRUPT_DWORDS: usize = CORRUPT_RANGE / core::mem::size_of::<u64>() + 1;
+
+// How the target is written, see strategy.rs
+const CORRUPT_STRATEGY: CorruptStrategy = CorruptStrategy::WriteZeros;
+static_assertions::const_assert!(CORRUPT_STRATEGY.dword_count() > 0);
+static_assertions::const_assert!(CORRUPT_STRATEGY.dword_count() <= CORRUPT_DWORDS);
+
+// The flash up to this tool's image end must not be targeted, see protected_flash_end
+const fn targets_above(min: usize) -> bool {
+    let mut i = 0;
+    while i < TARGET_ADDRESSES.len() {
+        if TARGET_ADDRESSES[i] < min {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
 
-// On the first page, this tool itself lies. Don't let it erase itself!
-// In dual bank mode, the first page is 4096 bytes, so we can't corrupt the first page.
-// If you are in single-bank mode, don't go below 8192
-static_assertions::const_assert!(APPROXIMATE_ADDRESS_TO_CORRUPT >= 8192);
+// Each attempt erases the page of its target, which would wipe out a corruption that was already
+// found on the same page. So every target needs a page of its own
+const fn targets_on_distinct_pages() -> bool {
+    let page_size = FLASH_PAGE_SIZE as usize;
+    let mut i = 0;
+    while i < TARGET_ADDRESSES.len() {
+        let mut j = i + 1;
+        while j < TARGET_ADDRESSES.len() {
+            if TARGET_ADDRESSES[i] / page_size == TARGET_ADDRESSES[j] / page_size {
+                return false;
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    true
+}
+
+// Page size of the supported devices (STM32L4x1/L4x2, single bank)
+const FLASH_PAGE_SIZE: u32 = 2048;
+
+/// End of the flash that holds this tool, as an address. The image end from the linker is rounded
+/// up to a power of two, as required for the MPU region, which also makes it a whole number of
+/// pages. This part of the flash is made read-only with the MPU, so that the tool can't overwrite
+/// itself even if an address is calculated wrongly at runtime, and no target may lie in it
+fn protected_flash_end() -> u32 {
+    let size = (image_end() - FLASH_BASE).max(FLASH_PAGE_SIZE);
+    FLASH_BASE + size.next_power_of_two()
+}
+
+// With the `uart-log` feature, all `rprintln!` calls go to USART2 instead of RTT
+#[cfg(feature = "uart-log")]
+macro_rules! rprintln {
+    ($($arg:tt)*) => {{
+        use core::fmt::Write;
+        let _ = writeln!(hw::Usart2Writer, $($arg)*);
+    }};
+}
+
+#[cfg(feature = "uart-log")]
+const UART_BAUD: u32 = 115_200;
+
+// With the `itm-log` feature, all `rprintln!` calls go to ITM stimulus port 0 via SWO
+#[cfg(feature = "itm-log")]
+macro_rules! rprintln {
+    ($($arg:tt)*) => {{
+        use core::fmt::Write;
+        let _ = writeln!(hw::ItmWriter, $($arg)*);
+    }};
+}
+
+#[cfg(feature = "itm-log")]
+const ITM_SWO_HZ: u32 = 2_000_000;
+
+// With the `semihosting-log` feature, all `rprintln!` calls go to the debugger via semihosting.
+// Without a debugger attached, this hard-faults
+#[cfg(feature = "semihosting-log")]
+macro_rules! rprintln {
+    ($($arg:tt)*) => {{
+        use core::fmt::Write;
+        let _ = writeln!(hw::SemihostingWriter, $($arg)*);
+    }};
+}
+
+// One parseable line with the reset counter, time and state in front, e.g.
+// `flash_log!(LogLevel::Info, "hit", &[("addr", address)])`. Debug lines are only printed with
+// the `verbose-log` feature
+macro_rules! flash_log {
+    ($level:expr, $msg:expr, $fields:expr) => {{
+        let level: LogLevel = $level;
+        if level.is_enabled() {
+            rprintln!("{} {} {}{}", log_prefix(), level, $msg, LogFields($fields));
+        }
+    }};
+}
 
+cfg_if::cfg_if! {
+    if #[cfg(any(
+        all(feature = "uart-log", feature = "itm-log"),
+        all(feature = "uart-log", feature = "semihosting-log"),
+        all(feature = "itm-log", feature = "semihosting-log"),
+    ))] {
+        compile_error!(
+            "Only one of the `uart-log`, `itm-log` and `semihosting-log` features can be enabled"
+        );
+    }
+}
+
+mod boards;
+#[cfg(not(any(feature = "uart-log", feature = "itm-log", feature = "semihosting-log")))]
+mod command;
 mod flash;
+mod history;
 mod hw;
+mod log;
+mod pac;
+mod search;
+mod state;
+mod strategy;
 
 use flash::*;
+use history::*;
 use hw::*;
+use log::*;
+use search::*;
+use state::*;
+use strategy::*;
 
 static RTC_INSTANCE: Mutex<RefCell<Option<Rtc>>> = Mutex::new(RefCell::new(None));
 
@@ -36,107 +160,566 @@ where
     interrupt::free(|cs| {
         let mut rtc_ref = RTC_INSTANCE.borrow(cs).borrow_mut();
         let rtc = rtc_ref.as_mut().expect("RTC not initialized");
-        f(rtc)
+        let result = f(rtc);
+
+        // Keep the checksum in sync with whatever the closure wrote
+        update_backup_crc(rtc);
+
+        result
     })
 }
 
+/// The prefix of a `flash_log!` line. Before the RTC is set up, the counter and state are 0
+fn log_prefix() -> LogPrefix {
+    let (resets, state) =
+        interrupt::free(|cs| match RTC_INSTANCE.borrow(cs).borrow_mut().as_mut() {
+            Some(rtc) => {
+                let reset_state = ResetState::load(rtc);
+                (reset_state.counter, reset_state.state)
+            }
+            None => (0, 0),
+        });
+
+    LogPrefix {
+        resets,
+        micros: uptime_us(),
+        state,
+    }
+}
+
+// Period of the blink codes that report the state after a panic or exception
+const BLINK_PERIOD_MS: u32 = 250;
+
+#[cfg(not(test))]
 #[panic_handler]
 fn panic_handler(_info: &core::panic::PanicInfo) -> ! {
-    set_red_led(true);
+    // Make sure everything logged so far actually leaves the chip
+    #[cfg(feature = "uart-log")]
+    flush_usart2();
 
     // Use the shared RTC instance safely
-    with_rtc(|rtc| {
+    let state = with_rtc(|rtc| {
+        let state = rtc.read_backup_register(3).unwrap();
         rtc.write_backup_register(0, 0);
+        state
     });
 
-    // Use HAL watchdog in panic loop
-    let dp = unsafe { stm32l4xx_hal::stm32::Peripherals::steal() };
-    let mut watchdog = IndependentWatchdog::new(dp.IWDG);
+    report_panic(&mut init_leds(), state)
+}
+
+/// Blinks the state we were in when the panic happened, the blink delay also feeds the watchdog
+fn report_panic(leds: &mut Leds, state: u32) -> ! {
     loop {
-        watchdog.feed();
+        blink_code(&mut leds.red, state as u8, BLINK_PERIOD_MS);
     }
 }
 
-macro_rules! bad_thing_happened {
-    () => {{
-        rprintln!("exception occurred");
-        // Turns on the green LED
-        let peripherals = unsafe { stm32l4x1::Peripherals::steal() };
-        peripherals.RTC.bkpr[0].write(|w| unsafe { w.bits(0) });
-
-        // Use HAL watchdog to feed in the loop
-        let dp = unsafe { stm32l4xx_hal::stm32::Peripherals::steal() };
-        let mut watchdog = IndependentWatchdog::new(dp.IWDG);
-        // watchdog.start(MillisDurationU32::millis(100));
-
-        let reg_content = peripherals.FLASH.eccr.read();
-        let is_flash_nmi: bool = {
-            reg_content.eccd().bit_is_set()
-        };
+/// Called once the current target address has an ECC error. Records what happened, then
+/// continues with the next target, or signals success if this was the last one
+fn target_hit(mut reset_state: ResetState, target: usize, error: EccError, leds: &mut Leds) -> ! {
+    let result = with_history(|history| {
+        let result = CorruptionResult::from_ecc_error(error, history.last_middle());
+        history.set_result(result);
+        result
+    });
+    rprintln!("Corruption result: {:?}", result);
+
+    // Continue with the next address, if there is one
+    if (reset_state.target_index as usize) + 1 < target_count(&reset_state) {
+        reset_state.target_index += 1;
+        reset_search(&mut reset_state);
+        with_rtc(|rtc| reset_state.save(rtc));
+        rprintln!("Hit {:#x}, continuing with the next address", target);
+        cortex_m::peripheral::SCB::sys_reset();
+    }
 
-        let dead_addr = reg_content.addr_ecc().bits() | ((reg_content.bk_ecc().bit() as u32) << 20);
+    // We're done! A reset starts over, which finds the corrupted addresses again
+    rprintln!("Hit {:#x}, all addresses done", target);
+    with_rtc(|rtc| rtc.write_backup_register(0, 0));
+    leds.green.set(true);
 
-        // If this is an ECC error in the area we wanted, turn on the green LED
-        if is_flash_nmi {
-            if dead_addr >= APPROXIMATE_ADDRESS_TO_CORRUPT as u32
-                && dead_addr < (APPROXIMATE_ADDRESS_TO_CORRUPT + CORRUPT_RANGE) as u32
-            {
-                // We're done!
-                set_green_led(true);
+    loop {
+        feed_watchdog();
+    }
+}
 
+macro_rules! bad_thing_happened {
+    ($leds:expr) => {{
+        let leds: &mut Leds = $leds;
+        rprintln!("exception occurred");
+        // Turns on the green LED
+        let reset_state = with_rtc(|rtc| ResetState::load(rtc));
+        let state = reset_state.state;
+        let target = current_target(&reset_state);
+        let range = current_range(&reset_state);
+
+        // If this is an ECC error in the area we wanted, turn on the green LED. FLASH_ECCR counts
+        // within a bank, the targets from the start of the flash
+        if let Some(error @ EccError::Uncorrectable { address }) = pending_ecc_error() {
+            let dead_addr = Flash::ecc_address_to_offset(address);
+            if dead_addr >= target as u32 && dead_addr < (target + range) as u32 {
+                target_hit(reset_state, target, error, leds);
+            } else {
+                // ECC error, but not where we wanted it: blink the state in red
+                with_rtc(|rtc| rtc.write_backup_register(0, 0));
                 loop {
-                    watchdog.feed();
+                    blink_code(&mut leds.red, state as u8, BLINK_PERIOD_MS);
                 }
-            } else {
-                set_red_led(true);
             }
         } else {
-            set_red_led(true);
-            set_blue_led(true);
+            // Some other fault: blink the state in blue with the red LED on
+            with_rtc(|rtc| rtc.write_backup_register(0, 0));
+            leds.red.set(true);
+            loop {
+                blink_code(&mut leds.blue, state as u8, BLINK_PERIOD_MS);
+            }
         }
-
-        loop {}
     }};
 }
 
 // Could reduce binary size by kind of just pointing these to the same function...
 // on the other hand, I don't care
+#[cfg(not(test))]
 #[exception]
 unsafe fn HardFault(_: &cortex_m_rt::ExceptionFrame) -> ! {
-    bad_thing_happened!()
+    bad_thing_happened!(&mut init_leds())
 }
 
+#[cfg(not(test))]
 #[exception]
-unsafe fn NonMaskableInt() -> ! {
+unsafe fn NonMaskableInt() {
+    // ECC errors caused by Flash::try_read_dword are expected, just go back to it
+    if handle_ecc_nmi_during_probe() {
+        return;
+    }
+
     // This should be the only thing getting called, as it's a non-maskable interrupt
-    bad_thing_happened!()
+    bad_thing_happened!(&mut init_leds())
 }
 
+#[cfg(not(test))]
 #[exception]
 unsafe fn DefaultHandler(_irqn: i16) -> ! {
-    bad_thing_happened!()
+    bad_thing_happened!(&mut init_leds())
+}
+
+#[cfg(not(test))]
+#[exception]
+fn SysTick() {
+    // Marks the end of the waiting time before the write
+    on_systick();
 }
 
 const STATE_BEFORE_WRITE: u32 = 1;
 const STATE_AFTER_WRITE: u32 = 2;
+// The binary search window became too small to continue, the result is in backup register 5
+const STATE_CONVERGED: u32 = 3;
+// The search did not succeed within MAX_ATTEMPTS attempts (or the linear scan ran out of range,
+// or a flash operation failed)
+const STATE_FAILED: u32 = 4;
+
+// Run from HSI16 instead of the 4MHz MSI, for a 4x finer timing resolution. Timing values from
+// a session with a different clock can't be reused
+const USE_HSI16: bool = false;
+
+// Whether the flash instruction and data caches are enabled. With the data cache, a read of the
+// target right after it was written may not reach the flash cells
+const CACHE_CONFIG: CacheConfig = CacheConfig {
+    icache: true,
+    dcache: true,
+};
+
+// Whether the flash prefetches instructions (FLASH_ACR.PRFTEN). A prefetch can read the target
+// on its own, see Flash::set_prefetch_enabled
+const ENABLE_PREFETCH: bool = true;
+
+// Watchdog configuration, see hw::iwdg_timeout_cycles for the resulting timeout.
+// The smallest possible timeout is ~125µs
+const IWDG_PRESCALER: IwdgPrescaler = IwdgPrescaler::Div4;
+const IWDG_RELOAD: u16 = 0;
+
+/// The watchdog that ends each attempt with a reset
+#[allow(dead_code)]
+enum TimingDevice {
+    /// The smallest timeout is ~125µs, and the LSI it runs on is not synchronous to the CPU
+    Iwdg,
+    /// Runs on PCLK1, so the timeout is exact in CPU cycles. The smallest timeout is 4096 cycles
+    Wwdg,
+}
+
+const TIMING_DEVICE: TimingDevice = TimingDevice::Iwdg;
+
+// Window watchdog configuration, only used with TimingDevice::Wwdg. The window is never hit, as
+// the WWDG is not refreshed during an attempt
+const WWDG_PRESCALER: WwdgPrescaler = WwdgPrescaler::Div1;
+const WWDG_WINDOW: u8 = 0x7F;
+const WWDG_COUNTER: u8 = 0x40;
+// The WWDG stops in Stop 2, so the attempt would never end
+static_assertions::const_assert!(
+    !(cfg!(feature = "stop2-between-attempts") && matches!(TIMING_DEVICE, TimingDevice::Wwdg))
+);
+
+// How long to sleep in Stop 2 at once while waiting for the watchdog, in RTC/16 ticks (0.5ms).
+// The watchdog keeps running in Stop 2 and normally resets us long before this
+#[cfg(feature = "stop2-between-attempts")]
+const STOP2_WAKEUP_TICKS: u16 = 2;
+
+// How long to wait for the user button before a new session starts. Resets by the watchdog
+// (or by the tool itself) continue a running session without waiting
+const BUTTON_TIMEOUT_MS: u32 = 10_000;
+
+// Maximum number of corruption attempts before giving up
+const MAX_ATTEMPTS: u32 = 500;
+
+// Option bytes to set at startup. `dual_bank` and `bfb2` only exist on dual-bank devices, the
+// STM32L4x1/L4x2 read them back as 0, so setting them there ends in STATE_FAILED.
+// Changing them resets the MCU once. Careful with `rdp_level`, see OptionBytes::set_rdp_level
+const OPTION_BYTES: OptionBytesConfig = OptionBytesConfig {
+    rdp_level: None,
+    dual_bank: None,
+    bfb2: None,
+};
+
+// PCROP area to set at startup, to test whether corrupting protected code leaks it. It can't be
+// removed again without an RDP regression, which erases the flash (see pcrop_set_region)
+const PCROP_REGION: Option<PcropRegion> = None;
+
+/// How the timing value is chosen between attempts
+#[allow(dead_code)]
+#[derive(Debug)]
+enum ScanMode {
+    /// Bisect the range between bottom and top. This assumes that waiting longer always moves the
+    /// reset from after the write towards before the write
+    BinarySearch,
+    /// Walk from bottom to top in fixed steps, for when the write timing varies too much for the
+    /// binary search to converge
+    LinearScan { step: u32 },
+}
+
+const SCAN_MODE: ScanMode = ScanMode::BinarySearch;
 
 const MAGIC_VALUE: u32 = 0x99999999;
 
-// Backup register use:
-// 0: Magic value to detect first boot
-// 1: Bottom of the waiting range (for binary search)
-// 2: Top of the waiting range
-// 3: State we are currently in (allows us to detect if last reset was before or after write)
-// 4: Reset counter
+// Write this to backup register 0 with the debugger (`set {int}0x40002850 = 0xCAFEBABE`) to
+// set the target at runtime: after the next reset, the tool waits for a line like
+// `addr=0x10000 range=0x20` (or `clear`) on RTT down channel 0.
+#[cfg(not(any(feature = "uart-log", feature = "itm-log", feature = "semihosting-log")))]
+const CONFIG_MAGIC_VALUE: u32 = 0xCAFEBABE;
+
+// The backup register layout is documented in state.rs
+
+/// Starts the search for the current target from scratch
+fn reset_search(reset_state: &mut ResetState) {
+    reset_state.bottom = 1;
+    // The write has to start before the watchdog fires, so search up to its timeout
+    reset_state.top = match TIMING_DEVICE {
+        TimingDevice::Iwdg => iwdg_timeout_cycles(IWDG_PRESCALER, IWDG_RELOAD, sysclk_hz()),
+        TimingDevice::Wwdg => wwdg_timeout_cycles(WWDG_PRESCALER, WWDG_COUNTER),
+    };
+    reset_state.state = 0;
+    reset_state.converged = 0;
+    reset_state.attempts = 0;
+}
+
+/// The address that is currently being targeted
+fn current_target(reset_state: &ResetState) -> usize {
+    // A target set at runtime replaces the whole list
+    if reset_state.runtime_range != 0 {
+        return reset_state.runtime_target as usize;
+    }
+
+    // Fall back to the first address if the index is out of range, e.g. because the list was
+    // changed between runs
+    *TARGET_ADDRESSES
+        .get(reset_state.target_index as usize)
+        .unwrap_or(&TARGET_ADDRESSES[0])
+}
+
+/// The range around [current_target] in which an ECC error counts as a hit
+fn current_range(reset_state: &ResetState) -> usize {
+    if reset_state.runtime_range != 0 {
+        reset_state.runtime_range as usize
+    } else {
+        CORRUPT_RANGE
+    }
+}
+
+/// How many addresses are targeted one after another
+fn target_count(reset_state: &ResetState) -> usize {
+    if reset_state.runtime_range != 0 {
+        1
+    } else {
+        TARGET_ADDRESSES.len()
+    }
+}
+
+/// Waits for a target command on RTT and stores it in the reset state. Invalid commands are
+/// reported and another one is awaited
+#[cfg(not(any(feature = "uart-log", feature = "itm-log", feature = "semihosting-log")))]
+fn read_target_command(input: &mut DownChannel, reset_state: &mut ResetState) {
+    use command::{parse_target_command, TargetCommand};
+
+    let mut line = [0u8; 64];
+    loop {
+        rprintln!("Waiting for `addr=<address> range=<range>` or `clear`");
+
+        // Collect a line, the bytes arrive whenever the host sends them
+        let mut len = 0;
+        while len == 0 || line[len - 1] != b'\n' {
+            if len == line.len() {
+                len = 0;
+            }
+            len += input.read(&mut line[len..]);
+        }
+
+        let command = core::str::from_utf8(&line[..len])
+            .ok()
+            .and_then(parse_target_command);
+        match command {
+            Some(TargetCommand::Clear) => {
+                reset_state.runtime_target = 0;
+                reset_state.runtime_range = 0;
+                return;
+            }
+            // Same restrictions as for TARGET_ADDRESSES. The range is limited by the size of the
+            // write buffer, which depends on CORRUPT_RANGE
+            Some(TargetCommand::Set { address, range })
+                if address >= (protected_flash_end() - FLASH_BASE) as usize
+                    && address % core::mem::size_of::<u64>() == 0
+                    && range > 0
+                    && range <= CORRUPT_RANGE =>
+            {
+                reset_state.runtime_target = address as u32;
+                reset_state.runtime_range = range as u32;
+                return;
+            }
+            Some(TargetCommand::Set { .. }) => rprintln!(
+                "The address must be 8 byte aligned and at least {:#x}, the range at most {:#x}",
+                protected_flash_end() - FLASH_BASE,
+                CORRUPT_RANGE
+            ),
+            None => rprintln!("Could not parse the command"),
+        }
+    }
+}
+
+/// Signals a converged binary search with a steady blue LED
+fn report_converged(leds: &mut Leds) -> ! {
+    leds.set_all(false, false, true);
+
+    loop {
+        feed_watchdog();
+    }
+}
 
+/// Why the search ended in `STATE_FAILED`. The cause is not stored, but each one leaves the
+/// registers in a different state
+fn failure_cause(reset_state: &ResetState) -> &'static str {
+    if reset_state.attempts >= MAX_ATTEMPTS {
+        "attempt limit reached"
+    } else if matches!(SCAN_MODE, ScanMode::LinearScan { .. })
+        && reset_state.bottom > reset_state.top
+    {
+        "scanned the whole range"
+    } else {
+        "flash operation failed"
+    }
+}
+
+/// Signals a failed binary search with the red and blue LEDs
+fn report_failed(leds: &mut Leds) -> ! {
+    leds.set_all(false, true, true);
+
+    loop {
+        feed_watchdog();
+    }
+}
+
+/// Gives up after a flash operation failed. Retrying would fail the same way, e.g. because the
+/// target page is write protected
+fn report_flash_error(reset_state: &mut ResetState, error: Error, leds: &mut Leds) -> ! {
+    reset_state.state = STATE_FAILED;
+    with_rtc(|rtc| reset_state.save(rtc));
+    flash_log!(
+        LogLevel::Error,
+        "flash operation failed",
+        &[("error", error as u32)]
+    );
+    report_failed(leds);
+}
+
+/// Parks the MCU when no session should be started. Only a reset gets us out of here
+fn report_idle(leds: &mut Leds) -> ! {
+    leds.set_all(false, false, false);
+
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
+
+/// Writes [OPTION_BYTES] and [PCROP_REGION] if the current option bytes differ and reloads them,
+/// which resets the MCU. This is only tried once: if they still differ after the reload, e.g.
+/// because a bit doesn't exist on this device, reloading again would loop forever, so we give up
+fn apply_option_bytes(flash: &mut Flash, reset_state: &mut ResetState, leds: &mut Leds) {
+    let current = flash.option_bytes_read();
+    let wanted = OPTION_BYTES.apply(current);
+    // Everything pcrop_set_region programs is compared, including PCROP_RDP
+    let pcrop = PCROP_REGION.filter(|&region| flash.pcrop_read() != Some(region));
+    if wanted == current && pcrop.is_none() {
+        reset_state.option_reload = 0;
+        return;
+    }
+
+    if reset_state.option_reload != 0 {
+        rprintln!(
+            "Option bytes are {:#010x} after the reload, wanted {:#010x}",
+            current.raw,
+            wanted.raw
+        );
+        rprintln!(
+            "PCROP is {:?} after the reload, wanted {:?}",
+            flash.pcrop_read(),
+            PCROP_REGION
+        );
+        report_flash_error(reset_state, Error::OptionsNotApplied, leds);
+    }
+
+    rprintln!(
+        "Changing option bytes from {:#010x} to {:#010x}, PCROP to {:?}",
+        current.raw,
+        wanted.raw,
+        pcrop
+    );
+    let result = with_flash_unlocked(flash, |flash_unlocked| {
+        if wanted != current {
+            flash_unlocked.option_bytes_write(wanted)?;
+        }
+        if let Some(region) = pcrop {
+            flash_unlocked.pcrop_set_region(region.start_page, region.end_page, region.rdp_prot)?;
+        }
+
+        // Saved right before the reset, so that the registers are up to date even on first boot
+        reset_state.option_reload = 1;
+        with_rtc(|rtc| reset_state.save(rtc));
+        flash_unlocked.reload_option_bytes()
+    });
+
+    if let Err(e) = result {
+        report_flash_error(reset_state, e, leds);
+    }
+}
+
+/// Moves the binary search window according to the outcome of the last attempt and returns the
+/// timing value for the next attempt
+fn scan_binary(reset_state: &mut ResetState, advance: bool, leds: &mut Leds) -> u32 {
+    let mut search = BinarySearch {
+        bottom: reset_state.bottom,
+        top: reset_state.top,
+        state: reset_state.state,
+        counter: reset_state.attempts,
+    };
+
+    // Garbage in the registers, e.g. from an older version of this tool
+    if !search.is_valid() {
+        rprintln!("Invalid search window, starting over");
+        reset_search(reset_state);
+        return scan_binary(reset_state, false, leds);
+    }
+
+    // If we are very close, the search has converged. Record the result instead of trying again
+    if search.is_converged() {
+        let middle = search.current_middle();
+        reset_state.state = STATE_CONVERGED;
+        reset_state.converged = middle;
+        with_rtc(|rtc| reset_state.save(rtc));
+        rprintln!("Binary search converged at {}", middle);
+        report_converged(leds);
+    }
+
+    if advance {
+        search.advance();
+    }
+
+    reset_state.bottom = search.bottom;
+    reset_state.top = search.top;
+
+    // We basically do a binary search over multiple resets to find the right time to corrupt
+    search.current_middle()
+}
+
+/// Moves the cursor (stored in the bottom register) up by `step` after every attempt and returns
+/// it as the timing value for the next attempt. Unlike the binary search, this does not assume
+/// that the outcome is monotone in the timing value.
+fn scan_linear(reset_state: &mut ResetState, advance: bool, step: u32, leds: &mut Leds) -> u32 {
+    if advance {
+        reset_state.bottom += step;
+    }
+
+    // We scanned the whole range without hitting the target
+    if reset_state.bottom > reset_state.top {
+        reset_state.state = STATE_FAILED;
+        with_rtc(|rtc| reset_state.save(rtc));
+        rprintln!("Linear scan reached {} without success", reset_state.top);
+        report_failed(leds);
+    }
+
+    reset_state.bottom
+}
+
+#[cfg(not(test))]
 #[entry]
 fn main() -> ! {
-    // Initialize RTT
-    rtt_init_print!();
+    // SAFETY: This is the first thing that runs, so nothing else uses the peripherals yet. The
+    // HAL types below are stolen separately, they are only used for the RTC
+    let mut peripherals = unsafe { pac::Peripherals::steal() };
+
+    // Breakpoints would otherwise end the attempt with a watchdog reset
+    freeze_iwdg_in_debug();
+    freeze_rtc_in_debug();
+
+    let mut leds = init_leds();
+
+    // Before anything that depends on the clock, like the UART baud rate
+    if USE_HSI16 {
+        init_clock_hsi16(&mut peripherals.RCC);
+    }
+    configure_systick_us(sysclk_hz());
+    apply_cache_config(CACHE_CONFIG);
+    if CORRUPT_STRATEGY.uses_dma() {
+        peripherals.RCC.ahb1enr.modify(|_, w| w.dma1en().set_bit());
+    }
+
+    // Initialize logging
+    #[cfg(not(any(feature = "uart-log", feature = "itm-log", feature = "semihosting-log")))]
+    let mut rtt_input = {
+        let channels = rtt_init! {
+            up: {
+                0: { size: 1024, name: "Terminal" }
+            }
+            down: {
+                0: { size: 64, name: "Terminal" }
+            }
+        };
+        set_print_channel(channels.up.0);
+        channels.down.0
+    };
+    #[cfg(feature = "uart-log")]
+    init_usart2(UART_BAUD, &peripherals.RCC, &peripherals.GPIOA);
+    #[cfg(feature = "itm-log")]
+    {
+        // SAFETY: Only the trace peripherals are used, nothing else touches them
+        let mut core_peripherals = unsafe { cortex_m::Peripherals::steal() };
+        init_itm(
+            &mut core_peripherals.TPIU,
+            &mut core_peripherals.DWT,
+            sysclk_hz(),
+            ITM_SWO_HZ,
+        );
+    }
 
-    rprintln!("Hello from STM32 via RTT!");
-    
-    let peripherals = unsafe { stm32l4x1::Peripherals::steal() };
+    rprintln!("Hello from STM32!");
+    // SAFETY: The HAL only gets the RCC, PWR and RTC, and only changes the RCC bits it needs for
+    // the RTC, so it does not undo the clock and logging setup above
     let dp = unsafe { stm32l4xx_hal::stm32::Peripherals::steal() };
     let mut rcc = dp.RCC.constrain();
     let mut pwr = dp.PWR.constrain(&mut rcc.apb1r1);
@@ -151,101 +734,277 @@ fn main() -> ! {
         RTC_INSTANCE.borrow(cs).replace(Some(rtc));
     });
 
+    // Load everything at once, so that a reset can never leave the registers half-updated.
+    // The checksum must be checked in the very first `with_rtc` call, as every call updates it
+    let (mut reset_state, crc_valid) =
+        with_rtc(|rtc| (ResetState::load(rtc), backup_crc_valid(rtc)));
+
+    // The debugger asked for a new target. Writing register 0 breaks the checksum, so this has to
+    // be checked before it. Afterwards, start over like on the first boot
+    #[cfg(not(any(feature = "uart-log", feature = "itm-log", feature = "semihosting-log")))]
+    if reset_state.magic == CONFIG_MAGIC_VALUE {
+        read_target_command(&mut rtt_input, &mut reset_state);
+        reset_state.magic = MAGIC_VALUE;
+        reset_state.target_index = 0;
+        reset_search(&mut reset_state);
+        with_rtc(|rtc| reset_state.save(rtc));
+        rprintln!("Target configured, resetting");
+        cortex_m::peripheral::SCB::sys_reset();
+    }
+
     // Basically detect the first boot and set the top/bottom of the range
-    let magic_val = with_rtc(|rtc| rtc.read_backup_register(0).unwrap());
-    if magic_val != MAGIC_VALUE {
+    if reset_state.magic != MAGIC_VALUE || !crc_valid {
         rprintln!("First boot detected, setting up backup registers...");
-        with_rtc(|rtc| {
-            rtc.write_backup_register(0, MAGIC_VALUE);
-            rtc.write_backup_register(1, 1);
-            rtc.write_backup_register(2, 1_000);
-            rtc.write_backup_register(3, 0);
-        });
+        reset_state.magic = MAGIC_VALUE;
+        reset_state.target_index = 0;
+        // Whatever is in these registers now was not set via RTT
+        reset_state.runtime_target = 0;
+        reset_state.runtime_range = 0;
+        // A new search may try to apply the option bytes again
+        reset_state.option_reload = 0;
+        reset_search(&mut reset_state);
     }
 
-    // This is a reset counter, which is interesting when debugging
-    with_rtc(|rtc| {
-        let cnt = rtc.read_backup_register(4).unwrap();
-        rtc.write_backup_register(4, cnt + 1);
-    });
+    let target = current_target(&reset_state);
+    rprintln!(
+        "Targeting {:#x} (address {} of {})",
+        target,
+        reset_state.target_index + 1,
+        target_count(&reset_state)
+    );
+    // The size of the image is only known after linking, so this can't be checked at compile time
+    let protected_size = (protected_flash_end() - FLASH_BASE) as usize;
+    if !targets_above(protected_size) || target < protected_size {
+        rprintln!(
+            "Targets must not lie below {:#x}, this tool is there",
+            protected_size
+        );
+        report_flash_error(&mut reset_state, Error::WriteProtection, &mut leds);
+    }
+
+    // Only watchdog resets are the result of an attempt, anything else (reset button, brown-out,
+    // debugger) must not move the search window
+    let reset_cause = read_reset_cause();
+    rprintln!("Reset cause: {:?}", reset_cause);
+    clear_reset_cause();
+    // The two watchdogs have separate flags in RCC_CSR, only the one timing the attempts counts
+    let watchdog_reset = match TIMING_DEVICE {
+        TimingDevice::Iwdg => reset_cause == ResetCause::IndependentWatchdog,
+        TimingDevice::Wwdg => reset_cause == ResetCause::WindowWatchdog,
+    };
 
-    let bottom = with_rtc(|rtc| rtc.read_backup_register(1).unwrap());
-    let top = with_rtc(|rtc| rtc.read_backup_register(2).unwrap());
-    let mut middle = (bottom + top) / 2;
+    // This is a reset counter, which is interesting when debugging
+    reset_state.counter += 1;
+
+    let mut flash = Flash::with_sysclk_hz(peripherals.FLASH, sysclk_hz());
+    // Only used by CorruptStrategy::DmaWrite
+    let mut dma = peripherals.DMA1;
+    apply_option_bytes(&mut flash, &mut reset_state, &mut leds);
+
+    with_history(|history| {
+        for (seq, entry) in history.iter() {
+            rprintln!(
+                "History #{}: middle={} ecc_address={:#x}",
+                seq,
+                entry.middle,
+                entry.ecc_address
+            );
+        }
 
-    // If we are very close, we have likely missed the exact time and need to try again
-    let very_similar = top - bottom < 5;
-    assert!(!very_similar);
+        if let Some(result) = history.result() {
+            rprintln!("Last corruption: {:?}", result);
+        }
+    });
 
-    let state = with_rtc(|rtc| rtc.read_backup_register(3).unwrap());
+    // A previous run already finished the search, so there is nothing left to do
+    if reset_state.state == STATE_CONVERGED {
+        with_rtc(|rtc| reset_state.save(rtc));
+        rprintln!("Binary search already converged at {}", reset_state.converged);
+        report_converged(&mut leds);
+    }
 
-    let mut bottom = bottom;
-    let mut top = top;
-    if state == STATE_BEFORE_WRITE {
-        // Apparently we run too long before the reset, so we need to go down
-        top = middle;
-        with_rtc(|rtc| rtc.write_backup_register(2, top));
-    } else if state == STATE_AFTER_WRITE {
-        // Apparently reset too late, so go up a bit
-        bottom = middle;
-        with_rtc(|rtc| rtc.write_backup_register(1, bottom));
+    if reset_state.state == STATE_FAILED {
+        with_rtc(|rtc| reset_state.save(rtc));
+        rprintln!(
+            "{:?} failed after {} attempts: {}",
+            SCAN_MODE,
+            reset_state.attempts,
+            failure_cause(&reset_state)
+        );
+        report_failed(&mut leds);
     }
 
-    // We basically do a binary search over multiple resets to find the right time to corrupt
-    middle = (bottom + top) / 2;
+    // Only watchdog resets during an attempt advance the search, so only those count towards the
+    // limit
+    let state = reset_state.state;
+    let advance = watchdog_reset && (state == STATE_BEFORE_WRITE || state == STATE_AFTER_WRITE);
+    if advance {
+        let (attempts, state) = count_attempt(reset_state.attempts, state, MAX_ATTEMPTS);
+        reset_state.attempts = attempts;
+        reset_state.state = state;
+
+        if state == STATE_FAILED {
+            with_rtc(|rtc| reset_state.save(rtc));
+            rprintln!("Giving up after {} attempts", reset_state.attempts);
+            report_failed(&mut leds);
+        }
+    } else {
+        // Retry with the same window
+        rprintln!("Not a watchdog reset, keeping the search window");
+    }
 
-    peripherals.RTC.bkpr[3].write(|w| unsafe { w.bits(STATE_BEFORE_WRITE) });
+    let middle = match SCAN_MODE {
+        ScanMode::BinarySearch => scan_binary(&mut reset_state, advance, &mut leds),
+        ScanMode::LinearScan { step } => scan_linear(&mut reset_state, advance, step, &mut leds),
+    };
 
-    set_green_led(false);
-    set_red_led(false);
-    set_blue_led(false);
+    reset_state.state = STATE_BEFORE_WRITE;
+    with_rtc(|rtc| reset_state.save(rtc));
 
-    // First of all, read all of the data to see if we get an interrupt
-    // If yes, we are already in a corrupted state - nice!
-    for i in 0..CORRUPT_RANGE {
-        let addr = (APPROXIMATE_ADDRESS_TO_CORRUPT as usize) + i;
+    leds.set_all(false, false, false);
 
-        let data = unsafe { core::ptr::read_volatile(addr as *const u8) };
+    // Don't start a new session on every power-up, only when asked to. Software resets come from
+    // moving on to the next target address, so they continue the session too
+    if !watchdog_reset && reset_cause != ResetCause::Software {
+        rprintln!("Press the user button within {}ms to start", BUTTON_TIMEOUT_MS);
+        if !wait_for_button_press(BUTTON_TIMEOUT_MS) {
+            rprintln!("No button press, going to sleep");
+            report_idle(&mut leds);
+        }
+    }
 
-        core::hint::black_box(data);
+    // First of all, read all of the data to see if there is an ECC error
+    // If yes, we are already in a corrupted state - nice!
+    rprintln!(
+        "Device ID {:#05x}, UID {:02x?}",
+        flash.read_device_id(),
+        flash.read_unique_device_id()
+    );
+    rprintln!(
+        "Read protection: {:?}, PCROP active: {}",
+        flash.read_protection_level(),
+        flash.is_pcrop_active()
+    );
+    let opts = flash.option_bytes_read();
+    rprintln!(
+        "Dual bank: {}, BFB2: {}, nBOOT0: {}",
+        opts.dual_bank(),
+        opts.bfb2(),
+        opts.nboot0()
+    );
+    if flash.is_pcrop_active() {
+        let (start, end) = flash.pcrop_read_region();
+        rprintln!("PCROP covers pages {} to {}", start, end);
+    }
+    flash.set_prefetch_enabled(ENABLE_PREFETCH);
+    rprintln!("Prefetch enabled: {}", flash.is_prefetch_enabled());
+    // A corrected error from an earlier read, e.g. while the startup code copied .data, would
+    // keep FLASH_ECCR from recording the address of the next one
+    flash.clear_ecc_flags();
+    flash.enable_ecc_nmi();
+    // Targets are physical offsets, like the addresses in FLASH_ECCR. With swapped banks, the
+    // CPU sees them elsewhere
+    let target_alias = flash.bank_alias(target as u32) as usize;
+    let first_dword = target_alias & !(core::mem::size_of::<u64>() - 1);
+    let range_end = target_alias + current_range(&reset_state);
+    // The watchdog reset cleared FLASH_ECCR, so whatever the last attempt left behind is only
+    // found by this probe. It goes into the history together with the attempt's timing value
+    let mut ecc_address = 0;
+    for addr in (first_dword..range_end).step_by(core::mem::size_of::<u64>()) {
+        match flash.try_read_dword(addr as u32) {
+            Err(error @ EccError::Uncorrectable { address }) => {
+                flash_log!(
+                    LogLevel::Info,
+                    "uncorrectable ECC error",
+                    &[("addr", address)]
+                );
+                if watchdog_reset {
+                    with_history(|history| history.push_pending(address));
+                }
+                target_hit(reset_state, target, error, &mut leds);
+            }
+            Err(EccError::Corrected { address }) => {
+                flash_log!(LogLevel::Warn, "corrected ECC error", &[("addr", address)]);
+                ecc_address = address;
+            }
+            Ok(_) => {}
+        }
+    }
+    if watchdog_reset {
+        with_history(|history| history.push_pending(ecc_address));
     }
+    with_history(|history| history.set_pending(middle));
+    // Corrected errors only matter to the probe. The NMI for uncorrectable errors during the
+    // attempt doesn't depend on ECCIE
+    flash.disable_ecc_nmi();
 
     // If we reach this, there was no corruption in the aimed area
-    let mut flash = Flash::new(peripherals.FLASH);
-    let page_number = flash.address_to_page_number(APPROXIMATE_ADDRESS_TO_CORRUPT as u32);
-
-    // We use the watchdog to time the corruption 
-    let mut watchdog = IndependentWatchdog::new(dp.IWDG);
-    
-    // First of all, we erase the page, as otherwise we can't write to it
-    let mut flash_unlocked = flash.unlock().unwrap();
-    flash_unlocked.erase_page(page_number).unwrap();
-
-    // After this, we have 0.125ms until we have to be within a write
-    watchdog.start(MilliSeconds::from_ticks(0));
-
-    // This gets us towards the time window...
-    // Also this definitely isn't exactly cycles, but it does not really matter which unit of time we use
-    for _ in 0..middle {
-        core::hint::black_box(0);
-    }
-
-    // Now we write to actually corrupt the flash.
-    // We basically hope that the watchdog setup was timed perfectly, so that we are in a phase of 
-    // flash writing where power must not be cut, and then we cut it
-    flash_unlocked
-        .write_dwords(
-            APPROXIMATE_ADDRESS_TO_CORRUPT as *mut usize,
-            // We write zero, because the flash page is all 0xff after erase 
-            &[0u64; CORRUPT_RANGE / core::mem::size_of::<u64>() + 1],
-        )
-        .unwrap();
+    if let Some(sector) = flash.sector_map().find(|s| s.contains(target as u32)) {
+        rprintln!(
+            "Target is in page {} of bank {} ({:#x}..{:#x})",
+            sector.number,
+            sector.bank,
+            sector.start_address,
+            sector.end_address
+        );
+    }
+    configure_mpu_flash_readonly(protected_flash_end());
+    let page = match flash.make_page(target_alias as u32) {
+        Ok(page) => page,
+        Err(e) => report_flash_error(&mut reset_state, e, &mut leds),
+    };
+
+    flash_log!(
+        LogLevel::Debug,
+        "attempt",
+        &[("addr", target_alias as u32), ("middle", middle)]
+    );
+    let result = with_flash_unlocked(&mut flash, |flash_unlocked| {
+        // First of all, we erase the page, as otherwise we can't write to it
+        if CORRUPT_STRATEGY.erases_page() {
+            flash_unlocked.erase_page(page)?;
+        }
+
+        // We use the watchdog to time the corruption. After this, we have until the watchdog
+        // timeout to be within a write
+        match TIMING_DEVICE {
+            TimingDevice::Iwdg => {
+                configure_iwdg_raw(IWDG_PRESCALER, IWDG_RELOAD);
+            }
+            TimingDevice::Wwdg => arm_wwdg(WWDG_PRESCALER, WWDG_WINDOW, WWDG_COUNTER),
+        }
+
+        // This gets us towards the time window. The timing value is measured in CPU cycles and
+        // controls the SysTick deadline, so it does not depend on how long the loop below takes
+        arm_systick_deadline(middle);
+        while !systick_deadline_reached() {}
+
+        // Now we write to actually corrupt the flash.
+        // We basically hope that the watchdog setup was timed perfectly, so that we are in a
+        // phase of flash writing where power must not be cut, and then we cut it
+        let address = target_alias as *mut usize;
+        match execute_write(CORRUPT_STRATEGY, flash_unlocked, &mut dma, address) {
+            // Without erase, or with an illegal access size, the write is expected to fail
+            Err(e) if CORRUPT_STRATEGY.expects_error() => {
+                rprintln!("Write failed as expected: {:?}", e);
+                Ok(())
+            }
+            result => result,
+        }
+    });
+
+    if let Err(e) = result {
+        report_flash_error(&mut reset_state, e, &mut leds);
+    }
 
     // If we reached this, we clearly didn't snipe early enough - after the next reset, we go lower
-    peripherals.RTC.bkpr[3].write(|w| unsafe { w.bits(STATE_AFTER_WRITE) });
-    set_blue_led(true);
+    reset_state.state = STATE_AFTER_WRITE;
+    with_rtc(|rtc| reset_state.save(rtc));
+    leds.blue.set(true);
 
     loop {
         // Wait for the watchdog to reset us
+        #[cfg(feature = "stop2-between-attempts")]
+        enter_stop2(WakeupSource::RtcWakeupTimer(STOP2_WAKEUP_TICKS));
     }
 }
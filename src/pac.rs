@@ -0,0 +1,28 @@
+//! The peripheral access crate for the selected chip. Exactly one of the `stm32l4x*` features
+//! picks the PAC module, everything else refers to the peripherals through this module.
+//!
+//! The flash, RCC, RTC, PWR and watchdog registers this tool uses are the same on all of them.
+//! The LEDs are plain push-pull outputs, so alternate function differences don't matter for
+//! them, but check the pins against your board:
+//! - `stm32l4x1` (STM32L431/L451): the default, used for the Nucleo-L452 since the L4x1 PAC
+//!   covers everything the tool touches.
+//! - `stm32l4x2` (STM32L432/L452): same GPIOB/GPIOC alternate functions as the L4x1, but the
+//!   32-pin L432 has no PC7 or PB14, so its LEDs need `board-custom`.
+//!
+//! The L47x/L48x lines are not offered: their dual-bank flash has 2 KB pages and 256 pages per
+//! bank, which doesn't match the page layout `flash` assumes for dual-bank mode.
+
+#[cfg(all(feature = "stm32l4x1", feature = "stm32l4x2"))]
+compile_error!("Only one of the `stm32l4x1` and `stm32l4x2` features can be enabled");
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "stm32l4x1")] {
+        pub use stm32l4::stm32l4x1 as device;
+    } else if #[cfg(feature = "stm32l4x2")] {
+        pub use stm32l4::stm32l4x2 as device;
+    } else {
+        compile_error!("Enable one of the `stm32l4x1` and `stm32l4x2` features");
+    }
+}
+
+pub use device::{Interrupt, Peripherals, DMA1, FLASH, GPIOA, GPIOB, GPIOC, IWDG, PWR, RCC, RTC};
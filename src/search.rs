@@ -0,0 +1,334 @@
+//! The binary search over the waiting time before the write, without the backup registers, so
+//! that it can be tested on the host.
+
+use crate::{STATE_AFTER_WRITE, STATE_BEFORE_WRITE, STATE_FAILED};
+
+/// The search has converged once the window is smaller than this many cycles
+const CONVERGENCE_WINDOW: u32 = 5;
+
+/// Binary search for the waiting time that makes the watchdog reset hit the write. Waiting longer
+/// is assumed to always move the reset from after the write towards before the write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BinarySearch {
+    /// Lower end of the window. Waiting this long, the write finished before the reset
+    pub bottom: u32,
+    /// Upper end of the window. Waiting this long, the reset came before the write
+    pub top: u32,
+    /// The state the last attempt ended in, `STATE_BEFORE_WRITE` or `STATE_AFTER_WRITE`
+    pub state: u32,
+    /// Number of times the window was moved
+    pub counter: u32,
+}
+
+impl BinarySearch {
+    /// The reset came before the write, so the next attempt has to wait less
+    pub fn advance_before_write(&mut self) {
+        self.top = self.current_middle();
+        self.counter += 1;
+    }
+
+    /// The write finished before the reset, so the next attempt has to wait longer
+    pub fn advance_after_write(&mut self) {
+        self.bottom = self.current_middle();
+        self.counter += 1;
+    }
+
+    /// Moves the window according to [BinarySearch::state]. Returns `false` and leaves the window
+    /// alone if the state does not say on which side of the write the last reset was.
+    pub fn advance(&mut self) -> bool {
+        match self.state {
+            STATE_BEFORE_WRITE => self.advance_before_write(),
+            STATE_AFTER_WRITE => self.advance_after_write(),
+            _ => return false,
+        }
+
+        true
+    }
+
+    /// Whether the window is too small to continue
+    pub fn is_converged(&self) -> bool {
+        self.top - self.bottom < CONVERGENCE_WINDOW
+    }
+
+    /// The waiting time for the next attempt
+    pub fn current_middle(&self) -> u32 {
+        self.bottom + (self.top - self.bottom) / 2
+    }
+
+    /// Whether the window makes sense. Garbage in the backup registers can produce a window with
+    /// `bottom > top`, which must be reset before any of the other methods is used.
+    pub fn is_valid(&self) -> bool {
+        self.bottom <= self.top
+    }
+}
+
+/// Counts an attempt that ended in a watchdog reset. Returns the new attempt count and the state
+/// to continue in: `state` while fewer than `max_attempts` attempts were made, `STATE_FAILED`
+/// once the limit is reached.
+pub fn count_attempt(attempts: u32, state: u32, max_attempts: u32) -> (u32, u32) {
+    let attempts = attempts + 1;
+    if attempts >= max_attempts {
+        return (attempts, STATE_FAILED);
+    }
+
+    (attempts, state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::mock::MockFlash;
+    use crate::flash::FlashProgram;
+    use crate::strategy::{execute_write, CorruptStrategy};
+    use crate::{MAX_ATTEMPTS, STATE_CONVERGED};
+
+    fn search(bottom: u32, top: u32, state: u32) -> BinarySearch {
+        BinarySearch {
+            bottom,
+            top,
+            state,
+            counter: 0,
+        }
+    }
+
+    /// Runs the search against a simulated device, where every waiting time below `boundary`
+    /// lets the write finish and every one at or above resets before the write. Returns the
+    /// converged middle and the number of attempts.
+    fn run_against(boundary: u32, top: u32) -> (u32, u32) {
+        let mut s = search(1, top, 0);
+        while !s.is_converged() {
+            s.state = if s.current_middle() < boundary {
+                STATE_AFTER_WRITE
+            } else {
+                STATE_BEFORE_WRITE
+            };
+            assert!(s.advance());
+            assert!(s.is_valid());
+        }
+        (s.current_middle(), s.counter)
+    }
+
+    #[test]
+    fn middle_of_window() {
+        assert_eq!(search(0, 100, 0).current_middle(), 50);
+        assert_eq!(search(10, 20, 0).current_middle(), 15);
+    }
+
+    #[test]
+    fn middle_rounds_down() {
+        assert_eq!(search(1, 4, 0).current_middle(), 2);
+    }
+
+    #[test]
+    fn middle_does_not_overflow() {
+        assert_eq!(
+            search(u32::MAX - 10, u32::MAX, 0).current_middle(),
+            u32::MAX - 5
+        );
+    }
+
+    #[test]
+    fn before_write_lowers_top() {
+        let mut s = search(0, 100, STATE_BEFORE_WRITE);
+        s.advance_before_write();
+        assert_eq!((s.bottom, s.top), (0, 50));
+    }
+
+    #[test]
+    fn after_write_raises_bottom() {
+        let mut s = search(0, 100, STATE_AFTER_WRITE);
+        s.advance_after_write();
+        assert_eq!((s.bottom, s.top), (50, 100));
+    }
+
+    #[test]
+    fn advance_follows_before_write_state() {
+        let mut s = search(0, 100, STATE_BEFORE_WRITE);
+        assert!(s.advance());
+        assert_eq!((s.bottom, s.top), (0, 50));
+    }
+
+    #[test]
+    fn advance_follows_after_write_state() {
+        let mut s = search(0, 100, STATE_AFTER_WRITE);
+        assert!(s.advance());
+        assert_eq!((s.bottom, s.top), (50, 100));
+    }
+
+    #[test]
+    fn advance_ignores_unknown_state() {
+        for state in [0, STATE_CONVERGED, STATE_FAILED, 0xDEAD_BEEF].iter() {
+            let mut s = search(0, 100, *state);
+            assert!(!s.advance());
+            assert_eq!(s, search(0, 100, *state));
+        }
+    }
+
+    #[test]
+    fn advance_does_not_change_state() {
+        let mut s = search(0, 100, STATE_AFTER_WRITE);
+        s.advance();
+        assert_eq!(s.state, STATE_AFTER_WRITE);
+    }
+
+    #[test]
+    fn counter_counts_advances() {
+        let mut s = search(0, 1000, STATE_BEFORE_WRITE);
+        s.advance();
+        s.state = STATE_AFTER_WRITE;
+        s.advance();
+        assert_eq!(s.counter, 2);
+    }
+
+    #[test]
+    fn counter_ignores_unknown_state() {
+        let mut s = search(0, 1000, 0);
+        s.advance();
+        assert_eq!(s.counter, 0);
+    }
+
+    #[test]
+    fn window_below_threshold_is_converged() {
+        assert!(search(100, 104, 0).is_converged());
+    }
+
+    #[test]
+    fn window_at_threshold_is_not_converged() {
+        assert!(!search(100, 105, 0).is_converged());
+    }
+
+    #[test]
+    fn empty_window_is_converged() {
+        assert!(search(7, 7, 0).is_converged());
+    }
+
+    #[test]
+    fn ordered_window_is_valid() {
+        assert!(search(1, 500, 0).is_valid());
+        assert!(search(5, 5, 0).is_valid());
+    }
+
+    #[test]
+    fn inverted_window_is_invalid() {
+        assert!(!search(500, 1, 0).is_valid());
+    }
+
+    #[test]
+    fn window_shrinks_with_every_advance() {
+        let mut s = search(1, 1000, STATE_BEFORE_WRITE);
+        let mut width = s.top - s.bottom;
+        for i in 0..8 {
+            s.state = if i % 2 == 0 {
+                STATE_BEFORE_WRITE
+            } else {
+                STATE_AFTER_WRITE
+            };
+            s.advance();
+            assert!(s.top - s.bottom < width);
+            width = s.top - s.bottom;
+        }
+    }
+
+    #[test]
+    fn converges_downwards() {
+        let (middle, _) = run_against(10, 1000);
+        assert!((8..=12).contains(&middle), "{}", middle);
+    }
+
+    #[test]
+    fn converges_upwards() {
+        let (middle, _) = run_against(990, 1000);
+        assert!((986..=994).contains(&middle), "{}", middle);
+    }
+
+    #[test]
+    fn converges_to_boundary() {
+        for boundary in (1..2000).step_by(37) {
+            let (middle, _) = run_against(boundary, 2000);
+            assert!(
+                middle + CONVERGENCE_WINDOW >= boundary && middle <= boundary + CONVERGENCE_WINDOW,
+                "boundary {}, converged at {}",
+                boundary,
+                middle
+            );
+        }
+    }
+
+    #[test]
+    fn converges_within_logarithmic_attempts() {
+        // The default IWDG timeout at 4MHz is 500 cycles, at 16MHz 2000
+        let (_, attempts) = run_against(1234, 2000);
+        assert!(attempts <= 11, "{}", attempts);
+    }
+
+    #[test]
+    fn converges_well_within_attempt_limit() {
+        let (_, attempts) = run_against(123_456, u32::MAX / 2);
+        assert!(attempts < crate::MAX_ATTEMPTS);
+    }
+
+    #[test]
+    fn converged_window_contains_boundary() {
+        let mut s = search(1, 1000, 0);
+        while !s.is_converged() {
+            s.state = if s.current_middle() < 321 {
+                STATE_AFTER_WRITE
+            } else {
+                STATE_BEFORE_WRITE
+            };
+            s.advance();
+        }
+        assert!(s.bottom <= 321 && 321 <= s.top);
+    }
+
+    #[test]
+    fn count_attempt_keeps_state_below_limit() {
+        assert_eq!(
+            count_attempt(0, STATE_AFTER_WRITE, 3),
+            (1, STATE_AFTER_WRITE)
+        );
+        assert_eq!(
+            count_attempt(1, STATE_BEFORE_WRITE, 3),
+            (2, STATE_BEFORE_WRITE)
+        );
+    }
+
+    #[test]
+    fn count_attempt_fails_at_limit() {
+        assert_eq!(count_attempt(2, STATE_BEFORE_WRITE, 3), (3, STATE_FAILED));
+    }
+
+    #[test]
+    fn attempts_stop_at_limit() {
+        const ADDRESS: usize = 0x800;
+
+        let mut flash = MockFlash::new();
+        let page = flash.make_page(ADDRESS as u32).unwrap();
+        // SAFETY: The mock doesn't access the DMA registers
+        let mut dma = unsafe { crate::pac::Peripherals::steal() }.DMA1;
+        let (mut attempts, mut state) = (0, STATE_BEFORE_WRITE);
+        let mut writes = 0;
+
+        // Every attempt is cut short by the watchdog, so the search never converges
+        while state != STATE_FAILED {
+            let mut unlocked = flash.unlock().unwrap();
+            unlocked.erase_page(page).unwrap();
+            execute_write(
+                CorruptStrategy::WriteZeros,
+                &mut unlocked,
+                &mut dma,
+                ADDRESS as *mut usize,
+            )
+            .unwrap();
+            writes += 1;
+
+            let next = count_attempt(attempts, STATE_BEFORE_WRITE, MAX_ATTEMPTS);
+            attempts = next.0;
+            state = next.1;
+        }
+
+        assert_eq!(attempts, MAX_ATTEMPTS);
+        assert_eq!(writes, MAX_ATTEMPTS);
+        assert!(flash.locked);
+    }
+}
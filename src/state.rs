@@ -0,0 +1,122 @@
+use stm32l4xx_hal::rtc::Rtc;
+
+use crate::hw::compute_backup_crc;
+
+// Backup register use:
+// 0: Magic value to detect first boot
+// 1: Bottom of the waiting range (for binary search), or the cursor for the linear scan
+// 2: Top of the waiting range
+// 3: State we are currently in (allows us to detect if last reset was before or after write)
+// 4: Reset counter
+// 5: Converged timing value (only valid in STATE_CONVERGED)
+// 6: Attempt counter. Unlike the reset counter, this only counts resets that advanced the search
+// 7: CRC32 over all other registers listed here, updated by every `with_rtc` call
+// 8: Index into TARGET_ADDRESSES of the address that is currently being targeted
+// 9: Target address set at runtime via RTT, replaces TARGET_ADDRESSES if register 10 is not 0
+// 10: Corrupt range set at runtime via RTT, 0 if none was set
+// 11: Not 0 if the option bytes were already reloaded once to apply OPTION_BYTES/PCROP_REGION
+const MAGIC_REGISTER: usize = 0;
+const BOTTOM_REGISTER: usize = 1;
+const TOP_REGISTER: usize = 2;
+const STATE_REGISTER: usize = 3;
+const COUNTER_REGISTER: usize = 4;
+const CONVERGED_REGISTER: usize = 5;
+const ATTEMPTS_REGISTER: usize = 6;
+const CRC_REGISTER: usize = 7;
+const TARGET_INDEX_REGISTER: usize = 8;
+const RUNTIME_TARGET_REGISTER: usize = 9;
+const RUNTIME_RANGE_REGISTER: usize = 10;
+const OPTION_RELOAD_REGISTER: usize = 11;
+
+/// Everything the tool remembers across resets, as stored in the RTC backup registers.
+/// The registers are always read and written as a whole, so that the state can be changed in
+/// Rust and then written back in one go.
+/// A register that is added here has to be added to [CRC_COVERED_REGISTERS] as well.
+#[derive(Debug, Clone, Copy)]
+pub struct ResetState {
+    pub magic: u32,
+    pub bottom: u32,
+    pub top: u32,
+    pub state: u32,
+    pub counter: u32,
+    pub converged: u32,
+    pub attempts: u32,
+    pub target_index: u32,
+    pub runtime_target: u32,
+    pub runtime_range: u32,
+    pub option_reload: u32,
+}
+
+impl ResetState {
+    /// Reads all backup registers
+    pub fn load(rtc: &mut Rtc) -> Self {
+        let mut read = |register| rtc.read_backup_register(register).unwrap();
+
+        ResetState {
+            magic: read(MAGIC_REGISTER),
+            bottom: read(BOTTOM_REGISTER),
+            top: read(TOP_REGISTER),
+            state: read(STATE_REGISTER),
+            counter: read(COUNTER_REGISTER),
+            converged: read(CONVERGED_REGISTER),
+            attempts: read(ATTEMPTS_REGISTER),
+            target_index: read(TARGET_INDEX_REGISTER),
+            runtime_target: read(RUNTIME_TARGET_REGISTER),
+            runtime_range: read(RUNTIME_RANGE_REGISTER),
+            option_reload: read(OPTION_RELOAD_REGISTER),
+        }
+    }
+
+    /// Writes all backup registers
+    pub fn save(&self, rtc: &mut Rtc) {
+        rtc.write_backup_register(MAGIC_REGISTER, self.magic);
+        rtc.write_backup_register(BOTTOM_REGISTER, self.bottom);
+        rtc.write_backup_register(TOP_REGISTER, self.top);
+        rtc.write_backup_register(STATE_REGISTER, self.state);
+        rtc.write_backup_register(COUNTER_REGISTER, self.counter);
+        rtc.write_backup_register(CONVERGED_REGISTER, self.converged);
+        rtc.write_backup_register(ATTEMPTS_REGISTER, self.attempts);
+        rtc.write_backup_register(TARGET_INDEX_REGISTER, self.target_index);
+        rtc.write_backup_register(RUNTIME_TARGET_REGISTER, self.runtime_target);
+        rtc.write_backup_register(RUNTIME_RANGE_REGISTER, self.runtime_range);
+        rtc.write_backup_register(OPTION_RELOAD_REGISTER, self.option_reload);
+    }
+}
+
+/// The backup registers that are covered by the checksum in [CRC_REGISTER]: all that
+/// [ResetState] persists
+const CRC_COVERED_REGISTERS: [usize; 11] = [
+    MAGIC_REGISTER,
+    BOTTOM_REGISTER,
+    TOP_REGISTER,
+    STATE_REGISTER,
+    COUNTER_REGISTER,
+    CONVERGED_REGISTER,
+    ATTEMPTS_REGISTER,
+    TARGET_INDEX_REGISTER,
+    RUNTIME_TARGET_REGISTER,
+    RUNTIME_RANGE_REGISTER,
+    OPTION_RELOAD_REGISTER,
+];
+
+/// Reads the backup registers that are covered by the checksum in [CRC_REGISTER]
+fn read_crc_covered_registers(rtc: &mut Rtc) -> [u32; CRC_COVERED_REGISTERS.len()] {
+    let mut regs = [0; CRC_COVERED_REGISTERS.len()];
+    for (reg, &register) in regs.iter_mut().zip(CRC_COVERED_REGISTERS.iter()) {
+        *reg = rtc.read_backup_register(register).unwrap();
+    }
+    regs
+}
+
+/// Recomputes the checksum over the backup registers and stores it
+pub fn update_backup_crc(rtc: &mut Rtc) {
+    let crc = compute_backup_crc(&read_crc_covered_registers(rtc));
+    rtc.write_backup_register(CRC_REGISTER, crc);
+}
+
+/// Checks whether the stored checksum matches the current backup register content.
+/// A mismatch means that the backup domain lost power and the registers contain garbage.
+pub fn backup_crc_valid(rtc: &mut Rtc) -> bool {
+    let crc = compute_backup_crc(&read_crc_covered_registers(rtc));
+    rtc.read_backup_register(CRC_REGISTER).unwrap() == crc
+}
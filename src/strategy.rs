@@ -0,0 +1,206 @@
+use crate::flash::{Error, FlashProgram};
+use crate::pac;
+use crate::CORRUPT_DWORDS;
+
+/// How the flash is written during an attempt
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub enum CorruptStrategy {
+    /// Erase the page and write zeros, which flips every bit
+    WriteZeros,
+    /// Write all ones without erasing the page first, to reproduce what happens when firmware
+    /// writes to flash that was not erased. The hardware refuses this with a programming error
+    WriteOnes,
+    /// Erase the page and write zeros to only the first `dwords` double-words, to study
+    /// partial writes without changing `CORRUPT_RANGE`
+    PartialWrite { dwords: usize },
+    /// Erase the page and write the given value to each double-word. Different patterns exercise
+    /// different ECC bit combinations, e.g. 0xDEADBEEFDEADBEEF, 0xAAAAAAAAAAAAAAAA or
+    /// 0x5555555555555555
+    WritePattern(u64),
+    /// Erase the page and write zeros with DMA1 `channel` (1 to 7) instead of the CPU, so that
+    /// the write competes with the DMA for the bus. DMA1 is clocked and passed in by `main`
+    DmaWrite { channel: u8 },
+    /// Erase the page and write a single 32-bit word, which the flash controller rejects with a
+    /// programming error, to study how the error flags interact with the reset
+    IllegalWrite,
+}
+
+impl CorruptStrategy {
+    /// Whether the page is erased before the write
+    pub fn erases_page(&self) -> bool {
+        !matches!(self, CorruptStrategy::WriteOnes)
+    }
+
+    /// Whether the write is expected to fail, so that its error does not end the search
+    pub fn expects_error(&self) -> bool {
+        matches!(
+            self,
+            CorruptStrategy::WriteOnes | CorruptStrategy::IllegalWrite
+        )
+    }
+
+    /// Whether DMA1 does the write
+    pub fn uses_dma(&self) -> bool {
+        matches!(self, CorruptStrategy::DmaWrite { .. })
+    }
+
+    /// How many double-words are written
+    pub const fn dword_count(&self) -> usize {
+        match self {
+            CorruptStrategy::PartialWrite { dwords } => *dwords,
+            _ => CORRUPT_DWORDS,
+        }
+    }
+
+    /// The value written to each double-word
+    fn pattern(&self) -> u64 {
+        match self {
+            CorruptStrategy::WriteOnes => u64::MAX,
+            CorruptStrategy::WritePattern(pattern) => *pattern,
+            CorruptStrategy::WriteZeros
+            | CorruptStrategy::PartialWrite { .. }
+            | CorruptStrategy::DmaWrite { .. }
+            | CorruptStrategy::IllegalWrite => 0,
+        }
+    }
+}
+
+/// Does the write of an attempt at `address`.
+/// The page must already be erased if [CorruptStrategy::erases_page] says so.
+pub fn execute_write<F: FlashProgram>(
+    strategy: CorruptStrategy,
+    flash: &mut F,
+    dma: &mut pac::DMA1,
+    address: *mut usize,
+) -> Result<(), Error> {
+    let data = &[strategy.pattern(); CORRUPT_DWORDS][..strategy.dword_count()];
+
+    match strategy {
+        CorruptStrategy::WriteOnes => flash.write_dwords_no_erase_check(address, data),
+        CorruptStrategy::DmaWrite { channel } => {
+            flash.write_dwords_dma(dma, channel, address, data)
+        }
+        CorruptStrategy::IllegalWrite => {
+            flash.write_word_illegal(address as *mut u32, strategy.pattern() as u32)
+        }
+        _ => flash.write_dwords(address, data),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::mock::MockFlash;
+
+    const ADDRESS: usize = 0x800;
+
+    /// The mock doesn't touch DMA1, it is only passed along
+    fn dma() -> pac::DMA1 {
+        // SAFETY: Nothing accesses the registers
+        unsafe { pac::Peripherals::steal() }.DMA1
+    }
+
+    #[test]
+    fn write_zeros_covers_the_range() {
+        let mut flash = MockFlash::new();
+        let page = flash.make_page(ADDRESS as u32).unwrap();
+        let mut unlocked = flash.unlock().unwrap();
+
+        unlocked.erase_page(page).unwrap();
+        execute_write(
+            CorruptStrategy::WriteZeros,
+            &mut unlocked,
+            &mut dma(),
+            ADDRESS as *mut usize,
+        )
+        .unwrap();
+        drop(unlocked);
+
+        assert!(flash.verify_dwords(ADDRESS as *mut usize, &[0; CORRUPT_DWORDS]));
+    }
+
+    #[test]
+    fn partial_write_leaves_the_rest_erased() {
+        let mut flash = MockFlash::new();
+        let mut unlocked = flash.unlock().unwrap();
+
+        let strategy = CorruptStrategy::PartialWrite { dwords: 1 };
+        execute_write(strategy, &mut unlocked, &mut dma(), ADDRESS as *mut usize).unwrap();
+        drop(unlocked);
+
+        assert!(flash.verify_dwords(ADDRESS as *mut usize, &[0, u64::MAX]));
+    }
+
+    #[test]
+    fn write_ones_fails_on_programmed_flash() {
+        let mut flash = MockFlash::new();
+        let mut unlocked = flash.unlock().unwrap();
+
+        execute_write(
+            CorruptStrategy::WriteZeros,
+            &mut unlocked,
+            &mut dma(),
+            ADDRESS as *mut usize,
+        )
+        .unwrap();
+
+        assert_eq!(
+            execute_write(
+                CorruptStrategy::WriteOnes,
+                &mut unlocked,
+                &mut dma(),
+                ADDRESS as *mut usize
+            ),
+            Err(Error::ProgrammingSequence)
+        );
+    }
+
+    #[test]
+    fn dma_write_covers_the_range() {
+        let mut flash = MockFlash::new();
+        let mut unlocked = flash.unlock().unwrap();
+
+        let strategy = CorruptStrategy::DmaWrite { channel: 1 };
+        execute_write(strategy, &mut unlocked, &mut dma(), ADDRESS as *mut usize).unwrap();
+        drop(unlocked);
+
+        assert!(flash.verify_dwords(ADDRESS as *mut usize, &[0; CORRUPT_DWORDS]));
+    }
+
+    #[test]
+    fn dma_write_rejects_invalid_channel() {
+        let mut flash = MockFlash::new();
+        let mut unlocked = flash.unlock().unwrap();
+
+        for channel in [0, 8].iter() {
+            let strategy = CorruptStrategy::DmaWrite { channel: *channel };
+            assert_eq!(
+                execute_write(strategy, &mut unlocked, &mut dma(), ADDRESS as *mut usize),
+                Err(Error::InvalidChannel)
+            );
+        }
+        drop(unlocked);
+
+        assert!(flash.verify_dwords(ADDRESS as *mut usize, &[u64::MAX]));
+    }
+
+    #[test]
+    fn illegal_write_is_rejected() {
+        let mut flash = MockFlash::new();
+        let mut unlocked = flash.unlock().unwrap();
+
+        assert_eq!(
+            execute_write(
+                CorruptStrategy::IllegalWrite,
+                &mut unlocked,
+                &mut dma(),
+                ADDRESS as *mut usize
+            ),
+            Err(Error::Illegal)
+        );
+        drop(unlocked);
+
+        assert!(flash.verify_dwords(ADDRESS as *mut usize, &[u64::MAX]));
+    }
+}